@@ -0,0 +1,207 @@
+//! Library interface for embedding cepler into other tooling.
+//!
+//! ```no_run
+//! use cepler::{Cepler, RecordOptions};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let cepler = Cepler::open("path/to/repo", "cepler.yml", None)?
+//!     .with_event_sink(|event: &cepler::Event| println!("{}", event));
+//! if let Some(report) = cepler.check("staging")? {
+//!     println!("Deploying trigger {}", report.trigger);
+//!     cepler.prepare("staging", &Default::default())?;
+//!     // ... deploy ...
+//!     cepler.record("staging", RecordOptions::default())?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{repo::*, workspace::*};
+use anyhow::*;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub use crate::{
+    config::{Config, EnvironmentConfig, GatesConfig},
+    database::{DeployState, FileDiff, FileIdent, FileState},
+    error::{CeplerError, EXIT_INTERNAL_ERROR, EXIT_NOTHING_TO_DEPLOY},
+    events::{Event, EventSink, StderrSink},
+    repo::{CommitHash, FileHash, FileMode, GitConfig, GitCredentials},
+    workspace::{CheckReport, RecordReport},
+};
+
+/// Options for [`Cepler::prepare`].
+#[derive(Debug, Default)]
+pub struct PrepareOptions {
+    /// Delete all files not referenced in the config.
+    pub force_clean: bool,
+    /// Only prepare files matching these patterns.
+    pub only: Vec<glob::Pattern>,
+    /// Propagate the upstream state recorded with this trigger.
+    pub from_upstream_state: Option<String>,
+    /// Prepare even if the environment is locked.
+    pub ignore_lock: bool,
+}
+
+/// Options for [`Cepler::record`].
+pub struct RecordOptions {
+    /// Commit the new state file.
+    pub commit: bool,
+    /// Checkout files to head after committing the state.
+    pub reset_head: bool,
+    /// Push head to this remote after recording.
+    pub push: Option<GitConfig>,
+    /// Only record files matching these patterns.
+    pub only: Vec<glob::Pattern>,
+    /// Propagate the upstream state recorded with this trigger.
+    pub from_upstream_state: Option<String>,
+    /// Record even if the environment is locked.
+    pub ignore_lock: bool,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        Self {
+            commit: true,
+            reset_head: false,
+            push: None,
+            only: Vec::new(),
+            from_upstream_state: None,
+            ignore_lock: false,
+        }
+    }
+}
+
+/// Handle on a repository and the cepler config describing its environments.
+pub struct Cepler {
+    repo_dir: PathBuf,
+    config_path: String,
+    config: Config,
+    gates: Option<GatesConfig>,
+    ignore_queue: bool,
+    events: Arc<dyn EventSink>,
+    credentials: GitCredentials,
+    known_hosts: Option<String>,
+}
+
+impl Cepler {
+    /// Opens the repository containing `repo_dir`.
+    /// `config_path` is relative to the root of the repository.
+    pub fn open(
+        repo_dir: impl AsRef<Path>,
+        config_path: &str,
+        gates: Option<GatesConfig>,
+    ) -> Result<Self> {
+        let repo_dir = Repo::open(repo_dir.as_ref(), None)?.workdir()?;
+        let config = Config::from_file(&repo_dir, config_path)?;
+        Ok(Self {
+            repo_dir,
+            config_path: config_path.to_string(),
+            config,
+            gates,
+            ignore_queue: false,
+            events: Arc::new(|_: &Event| {}),
+            credentials: GitCredentials::default(),
+            known_hosts: None,
+        })
+    }
+
+    /// Always propagate the current state of the previous environment.
+    pub fn ignore_queue(mut self, ignore_queue: bool) -> Self {
+        self.ignore_queue = ignore_queue;
+        self
+    }
+
+    /// Sets the sink receiving progress events. Events are discarded by default.
+    pub fn with_event_sink(mut self, events: impl EventSink + 'static) -> Self {
+        self.events = Arc::new(events);
+        self
+    }
+
+    /// Sets the credentials and known_hosts used to fetch submodules
+    /// and the history and objects missing from shallow and partial clones.
+    pub fn with_credentials(
+        mut self,
+        credentials: GitCredentials,
+        known_hosts: Option<String>,
+    ) -> Self {
+        self.credentials = credentials;
+        self.known_hosts = known_hosts;
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Checks whether `env` needs deploying.
+    pub fn check(&self, env: &str) -> Result<Option<CheckReport>> {
+        let (env, gate) = self.environment(env)?;
+        self.workspace()?.check(env, gate)
+    }
+
+    /// Lists all files relevant to `env`.
+    pub fn ls(&self, env: &str) -> Result<Vec<String>> {
+        let (env, gate) = self.environment(env)?;
+        self.workspace()?.ls(env, gate)
+    }
+
+    /// Prepares the working directory for deploying `env`.
+    pub fn prepare(&self, env: &str, options: &PrepareOptions) -> Result<()> {
+        let (env, gate) = self.environment(env)?;
+        let mut ws = self.workspace()?;
+        if options.ignore_lock {
+            ws.ignore_lock();
+        }
+        if let Some(trigger) = options.from_upstream_state.as_ref() {
+            ws.target_upstream_state(env, trigger)?;
+        }
+        ws.prepare(env, gate, options.force_clean, &options.only)
+    }
+
+    /// Records the state of the working directory as deployed to `env`.
+    pub fn record(&self, env: &str, options: RecordOptions) -> Result<RecordReport> {
+        let (env, gate) = self.environment(env)?;
+        let mut ws = self.workspace()?;
+        ws.set_commit_config(self.config.commit.clone())?;
+        if options.ignore_lock {
+            ws.ignore_lock();
+        }
+        if let Some(trigger) = options.from_upstream_state.as_ref() {
+            ws.target_upstream_state(env, trigger)?;
+        }
+        ws.record_env(
+            env,
+            gate,
+            options.commit,
+            options.reset_head,
+            options.push,
+            &options.only,
+        )
+    }
+
+    fn workspace(&self) -> Result<Workspace> {
+        let mut ws = Workspace::new(
+            &self.repo_dir,
+            &self.config.scope,
+            self.config_path.clone(),
+            self.ignore_queue,
+        )?;
+        ws.set_event_sink(Arc::clone(&self.events));
+        ws.set_first_parent(self.config.first_parent);
+        ws.set_credentials(self.credentials.clone(), self.known_hosts.clone());
+        Ok(ws)
+    }
+
+    fn environment(&self, name: &str) -> Result<(&EnvironmentConfig, Option<String>)> {
+        let env = self.config.environment(name)?;
+        let gate = if let Some(gates) = self.gates.as_ref() {
+            gates.get_gate(name)?
+        } else {
+            None
+        };
+        Ok((env, gate))
+    }
+}
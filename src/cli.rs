@@ -2,29 +2,49 @@ use super::{
     concourse::{self},
     config::*,
     database::Database,
+    error::*,
+    events::Event,
+    metrics,
     repo::*,
+    report, serve,
     workspace::Workspace,
 };
 use anyhow::*;
 use clap::{clap_app, crate_version, App, ArgMatches};
-use std::path::Path;
+use serde::Serialize;
+use std::{collections::HashMap, path::Path, process::Command, sync::Arc, time::Duration};
 
 fn app() -> App<'static, 'static> {
     let app = clap_app!(cepler =>
         (version: crate_version!())
         (@setting VersionlessSubcommands)
         (@setting SubcommandRequiredElseHelp)
-        (@arg CONFIG_FILE: -c --("config") env("CEPLER_CONF") default_value("cepler.yml") "Cepler config file")
+        (@arg CONFIG_FILE: -c --("config") env("CEPLER_CONF") default_value("cepler.yml") +multiple number_of_values(1) "Cepler config file. Can be given multiple times for 'check --all', 'watch', 'serve', 'metrics' and 'report'")
         (@arg IGNORE_QUEUE: --("ignore-queue") "Ignore the propagation queue")
+        (@arg FIRST_PARENT: --("first-parent") "Only follow the first parent of merge commits when looking for triggers and file changes")
         (@arg GATES_FILE: -g --("gates") +takes_value env("CEPLER_GATES") "Cepler gate file")
         (@arg GATES_BRANCH: --("gates-branch") +takes_value requires_all(&["GATES_FILE"]) env("GATES_BRANCH") "Branch to find the gate file")
-        (@arg CLONE_DIR: --("clone") +takes_value requires_all(&["GIT_URL", "GIT_PRIVATE_KEY"]) "Clone the repository into <dir>. Pulls latest changes if already present.")
+        (@arg REPO_DIR: --("repo") +takes_value env("CEPLER_REPO") conflicts_with[CLONE_DIR] "Path to the repository (defaults to the repository containing the current directory)")
+        (@arg CLONE_DIR: --("clone") +takes_value requires_all(&["GIT_URL"]) "Clone the repository into <dir>. Pulls latest changes if already present.")
+        (@arg CLONE_DEPTH: --("clone-depth") +takes_value requires_all(&["CLONE_DIR"]) env("CEPLER_CLONE_DEPTH") "Only clone the last <n> commits. History is deepened when it is needed")
+        (@arg CLONE_BLOBLESS: --("clone-blobless") requires_all(&["CLONE_DIR"]) "Clone without file contents. They are fetched when they are needed")
         (@arg GIT_URL: --("git-url") +takes_value env("GIT_URL") "Remote url for --clone option")
-        (@arg GIT_PRIVATE_KEY: --("git-private-key") +takes_value env("GIT_PRIVATE_KEY") "Private key for --clone option")
+        (@arg GIT_PRIVATE_KEY: --("git-private-key") +takes_value env("GIT_PRIVATE_KEY") conflicts_with[GIT_PRIVATE_KEY_FILE] "Private key for ssh remotes (defaults to using ssh-agent)")
+        (@arg GIT_PRIVATE_KEY_FILE: --("git-private-key-file") +takes_value env("GIT_PRIVATE_KEY_FILE") "Path to the private key for ssh remotes")
+        (@arg GIT_PASSPHRASE: --("git-passphrase") +takes_value env("GIT_PASSPHRASE") "Passphrase of the private key")
+        (@arg GIT_USERNAME: --("git-username") +takes_value env("GIT_USERNAME") "Username for the remote")
+        (@arg GIT_PASSWORD: --("git-password") +takes_value env("GIT_PASSWORD") "Password or token for https remotes (defaults to using the git credential helper)")
+        (@arg KNOWN_HOSTS: --("known-hosts") +takes_value env("GIT_KNOWN_HOSTS") "known_hosts file to verify the host key of ssh remotes against")
         (@arg GIT_BRANCH: --("git-branch") +takes_value default_value("main") env("GIT_BRANCH") "Branch for --clone option")
         (@subcommand check =>
-          (about: "Check wether the environment needs deploying. Exit codes: 0 - needs deploying; 1 - internal error; 2 - nothing to deploy")
+          (about: "Check wether the environment needs deploying. Exit codes: 0 - needs deploying; 1 - internal error; 2 - nothing to deploy; 12 - previous environment not deployed yet")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") required_unless[ALL] +takes_value "The cepler environment")
+          (@arg ALL: --("all") conflicts_with[ENVIRONMENT] "Check all environments of all given configs. Without -c every cepler*.yml below the current directory is checked")
+        )
+        (@subcommand diff =>
+          (about: "Show the files that would change on the next deploy")
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+          (@arg CONTENT: --("content") "Print a unified diff of the file contents (LFS files are shown by their object ids)")
         )
         (@subcommand ls =>
           (about: "List all files relevent to a given environment")
@@ -39,20 +59,73 @@ fn app() -> App<'static, 'static> {
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
           (@arg NO_COMMIT: --("no-commit") "Don't commit the new state")
           (@arg RESET_HEAD: --("reset-head") "Checkout files to head after committing the state")
-          (@arg PUSH: --("push") requires_all(&["RESET_HEAD", "GIT_URL", "GIT_PRIVATE_KEY"]) "Push head to remote")
+          (@arg PUSH: --("push") requires_all(&["RESET_HEAD", "GIT_URL"]) "Push head to remote")
           (@arg GIT_URL: --("git-url") +takes_value env("GIT_URL") "Remote url for --clone option")
-          (@arg GIT_PRIVATE_KEY: --("git-private-key") +takes_value env("GIT_PRIVATE_KEY") "Private key for --clone option")
+          (@arg GIT_PRIVATE_KEY: --("git-private-key") +takes_value env("GIT_PRIVATE_KEY") conflicts_with[GIT_PRIVATE_KEY_FILE] "Private key for ssh remotes (defaults to using ssh-agent)")
+          (@arg GIT_PRIVATE_KEY_FILE: --("git-private-key-file") +takes_value env("GIT_PRIVATE_KEY_FILE") "Path to the private key for ssh remotes")
+          (@arg GIT_PASSPHRASE: --("git-passphrase") +takes_value env("GIT_PASSPHRASE") "Passphrase of the private key")
+          (@arg GIT_USERNAME: --("git-username") +takes_value env("GIT_USERNAME") "Username for the remote")
+          (@arg GIT_PASSWORD: --("git-password") +takes_value env("GIT_PASSWORD") "Password or token for https remotes (defaults to using the git credential helper)")
+          (@arg KNOWN_HOSTS: --("known-hosts") +takes_value env("GIT_KNOWN_HOSTS") "known_hosts file to verify the host key of ssh remotes against")
           (@arg GIT_BRANCH: --("git-branch") +takes_value default_value("main") env("GIT_BRANCH") "Branch for --clone option")
+          (@arg FORCE: --("force") "Record even if the environment is locked")
+          (@arg ONLY: --("only") +takes_value +multiple number_of_values(1) "Only record files matching <glob> (relative to the config with relative_paths). Other files keep their last recorded state")
+          (@arg FROM_UPSTREAM_STATE: --("from-upstream-state") +takes_value "Propagate the upstream state recorded with <trigger-commit> instead of the next one in the queue")
         )
         (@subcommand prepare =>
           (about: "Prepare workspace for hook execution")
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
           (@arg FORCE_CLEAN: --("force-clean") "Delete all files not referenced in cepler.yml")
+          (@arg FORCE: --("force") "Prepare even if the environment is locked")
+          (@arg ONLY: --("only") +takes_value +multiple number_of_values(1) "Only prepare files matching <glob> (relative to the config with relative_paths). Other files are reset to their last recorded state")
+          (@arg FROM_UPSTREAM_STATE: --("from-upstream-state") +takes_value "Propagate the upstream state recorded with <trigger-commit> instead of the next one in the queue")
         )
         (@subcommand reproduce =>
           (about: "Reproduce workspace according to last recorded state")
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
           (@arg FORCE_CLEAN: --("force-clean") "Delete all files not referenced in cepler.yml")
+          (@arg AT: --("at") +takes_value "Reproduce the state recorded with <trigger-commit> or <history-index> (0 is the current state, 1 the one before...)")
+          (@arg OUTPUT: --("output") +takes_value conflicts_with[FORCE_CLEAN] "Write the files to <dir> instead of the working directory. Submodules are written as empty directories")
+        )
+        (@subcommand lock =>
+          (about: "Lock an environment to stop it from being deployed")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+          (@arg REASON: --("reason") +required +takes_value "The reason for locking the environment")
+          (@arg NO_COMMIT: --("no-commit") "Don't commit the lock")
+        )
+        (@subcommand unlock =>
+          (about: "Unlock a previously locked environment")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+          (@arg NO_COMMIT: --("no-commit") "Don't commit the removal of the lock")
+        )
+        (@subcommand status =>
+          (about: "Show the recorded trigger and lock status of environments")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +takes_value "The cepler environment (defaults to all)")
+        )
+        (@subcommand watch =>
+          (about: "Poll the repository and report every environment that gains a new trigger")
+          (@arg ENVIRONMENT: -e --("environment") +takes_value +multiple number_of_values(1) "Only watch the given environments (defaults to all)")
+          (@arg INTERVAL: --("interval") +takes_value default_value("60") "Seconds to wait between polls")
+          (@arg EXEC: --("exec") +takes_value "Run <command> via 'sh -c' for every new trigger instead of printing a JSON line. CEPLER_DEPLOYMENT, CEPLER_ENVIRONMENT, CEPLER_TRIGGER and CEPLER_CONF are set")
+          (@arg ONCE: --("once") "Poll only once and exit")
+        )
+        (@subcommand report =>
+          (@setting SubcommandRequiredElseHelp)
+          (about: "Reports computed from the recorded history")
+          (@subcommand lead_time =>
+            (name: "lead-time")
+            (about: "Show how long changes take from being authored to being recorded and how often environments are deployed")
+            (@arg ENVIRONMENT: -e --("environment") +takes_value +multiple number_of_values(1) "Only report the given environments (defaults to all)")
+            (@arg FORMAT: --("format") +takes_value possible_values(&["csv", "json"]) default_value("csv") "Output format")
+          )
+        )
+        (@subcommand metrics =>
+          (about: "Print metrics about all environments in the Prometheus text format")
+        )
+        (@subcommand serve =>
+          (about: "Serve read-only JSON endpoints describing the environments")
+          (@arg LISTEN: --("listen") +takes_value default_value("127.0.0.1:8080") "Address to listen on")
+          (@arg REFRESH: --("refresh") +takes_value default_value("60") "Seconds between refreshes of the repository")
         )
         (@subcommand concourse =>
          (@setting SubcommandRequiredElseHelp)
@@ -77,63 +150,133 @@ fn app() -> App<'static, 'static> {
 pub fn run() -> Result<()> {
     let matches = app().get_matches();
     let ignore_queue = matches.is_present("IGNORE_QUEUE");
-    if let Some(dir) = matches.value_of("CLONE_DIR") {
-        let conf = GitConfig {
-            url: matches.value_of("GIT_URL").unwrap().to_string(),
-            branch: matches.value_of("GIT_BRANCH").unwrap().to_string(),
-            gates_branch: matches.value_of("GATES_BRANCH").map(|b| b.to_string()),
-            private_key: matches.value_of("GIT_PRIVATE_KEY").unwrap().to_string(),
-            dir: dir.to_string(),
-        };
+    if let Some(conf) = clone_config_from_matches(&matches)? {
+        let dir = conf.dir.clone();
         let path = std::path::Path::new(&dir);
         if !path.exists() || path.read_dir()?.next().is_none() {
             Repo::clone(conf)?;
             std::env::set_current_dir(dir)?;
         } else {
             std::env::set_current_dir(dir)?;
-            Repo::open(None)?.pull(conf)?;
+            Repo::open(Path::new("."), None)?.pull(conf)?;
         }
     }
 
+    if let ("concourse", Some(sub_matches)) = matches.subcommand() {
+        return match sub_matches.subcommand() {
+            ("check", Some(_)) => concourse_check(),
+            ("ci_in", Some(matches)) => concourse_in(matches),
+            ("ci_out", Some(matches)) => concourse_out(matches),
+            _ => unreachable!(),
+        };
+    }
+
+    let workdir =
+        Repo::open(Path::new(matches.value_of("REPO_DIR").unwrap_or(".")), None)?.workdir()?;
     match matches.subcommand() {
         ("ls", Some(sub_matches)) => ls(
             sub_matches,
-            conf_from_matches(&matches)?,
-            gates_from_matches(&matches)?,
+            &matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+            gates_from_matches(&matches, &workdir)?,
+            ignore_queue,
+        ),
+        ("check", Some(sub_matches)) if sub_matches.is_present("ALL") => check_all(
+            &matches,
+            &workdir,
+            configs_from_matches(&matches, &workdir)?,
+            gates_from_matches(&matches, &workdir)?,
             ignore_queue,
         ),
         ("check", Some(sub_matches)) => check(
             sub_matches,
-            conf_from_matches(&matches)?,
-            gates_from_matches(&matches)?,
+            &matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+            gates_from_matches(&matches, &workdir)?,
+            ignore_queue,
+        ),
+        ("diff", Some(sub_matches)) => diff(
+            sub_matches,
+            &matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+            gates_from_matches(&matches, &workdir)?,
             ignore_queue,
         ),
         ("prepare", Some(sub_matches)) => prepare(
             sub_matches,
-            conf_from_matches(&matches)?,
-            gates_from_matches(&matches)?,
+            &matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+            gates_from_matches(&matches, &workdir)?,
             ignore_queue,
         ),
-        ("reproduce", Some(sub_matches)) => reproduce(sub_matches, conf_from_matches(&matches)?),
+        ("reproduce", Some(sub_matches)) => reproduce(
+            sub_matches,
+            &matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+        ),
         ("record", Some(sub_matches)) => record(
             sub_matches,
-            conf_from_matches(&matches)?,
-            gates_from_matches(&matches)?,
+            &matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+            gates_from_matches(&matches, &workdir)?,
             ignore_queue,
         ),
-        ("latest", Some(sub_matches)) => latest(sub_matches, conf_from_matches(&matches)?),
-        ("concourse", Some(sub_matches)) => match sub_matches.subcommand() {
-            ("check", Some(_)) => concourse_check(),
-            ("ci_in", Some(matches)) => concourse_in(matches),
-            ("ci_out", Some(matches)) => concourse_out(matches),
+        ("latest", Some(sub_matches)) => latest(
+            sub_matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+        ),
+        ("lock", Some(sub_matches)) => lock(
+            sub_matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+        ),
+        ("unlock", Some(sub_matches)) => unlock(
+            sub_matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+        ),
+        ("watch", Some(sub_matches)) => watch(sub_matches, &matches, &workdir, ignore_queue),
+        ("report", Some(sub_matches)) => match sub_matches.subcommand() {
+            ("lead-time", Some(sub_matches)) => lead_time(sub_matches, &matches, &workdir),
             _ => unreachable!(),
         },
+        ("metrics", Some(_)) => {
+            let configs = configs_from_matches(&matches, &workdir)?;
+            let gates = gates_from_matches(&matches, &workdir)?;
+            print!(
+                "{}",
+                metrics::render(
+                    &workdir,
+                    &configs,
+                    gates.as_ref(),
+                    ignore_queue,
+                    &credentials_from_matches(&matches),
+                    known_hosts_from_matches(&matches)?,
+                )?
+            );
+            Ok(())
+        }
+        ("serve", Some(sub_matches)) => serve(sub_matches, &matches, &workdir, ignore_queue),
+        ("status", Some(sub_matches)) => status(
+            sub_matches,
+            &workdir,
+            conf_from_matches(&matches, &workdir)?,
+        ),
         _ => unreachable!(),
     }
 }
 
 fn check(
     matches: &ArgMatches,
+    root_matches: &ArgMatches,
+    workdir: &Path,
     (config, config_path): (Config, String),
     gates: Option<GatesConfig>,
     ignore_queue: bool,
@@ -144,18 +287,293 @@ fn check(
     } else {
         None
     };
-    let ws = Workspace::new(&config.scope, config_path.clone(), ignore_queue)?;
-    let env = config.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config_path
-    ))?;
+    let ws = workspace(root_matches, workdir, &config, config_path, ignore_queue)?;
+    let env = config.environment(env)?;
     match ws.check(env, gate)? {
         None => {
             println!("Nothing new to deploy");
-            std::process::exit(2);
+            std::process::exit(EXIT_NOTHING_TO_DEPLOY);
+        }
+        Some(report) => {
+            println!(
+                "Found new state to deploy - trigger commit {}",
+                report.trigger
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_all(
+    root_matches: &ArgMatches,
+    workdir: &Path,
+    configs: Vec<(Config, String)>,
+    gates: Option<GatesConfig>,
+    ignore_queue: bool,
+) -> Result<()> {
+    let triggers = find_triggers(
+        root_matches,
+        workdir,
+        configs,
+        gates,
+        ignore_queue,
+        &[],
+        false,
+    )?;
+    if triggers.is_empty() {
+        println!("Nothing new to deploy");
+        std::process::exit(EXIT_NOTHING_TO_DEPLOY);
+    }
+    for trigger in triggers {
+        println!(
+            "{}/{} - trigger commit {} ({})",
+            trigger.deployment, trigger.environment, trigger.trigger, trigger.config
+        );
+    }
+    Ok(())
+}
+
+/// An environment that needs deploying.
+#[derive(Serialize)]
+struct Trigger {
+    deployment: String,
+    environment: String,
+    trigger: String,
+    config: String,
+}
+
+/// Checks the environments of all `configs` (or only those named in `only`).
+/// The repository is opened once but each environment still walks the history on its own.
+fn find_triggers(
+    root_matches: &ArgMatches,
+    workdir: &Path,
+    configs: Vec<(Config, String)>,
+    gates: Option<GatesConfig>,
+    ignore_queue: bool,
+    only: &[&str],
+    quiet: bool,
+) -> Result<Vec<Trigger>> {
+    let mut repo = Repo::open(workdir, None)?;
+    repo.set_credentials(
+        credentials_from_matches(root_matches),
+        known_hosts_from_matches(root_matches)?,
+    );
+    let mut triggers = Vec::new();
+    for (config, config_path) in configs {
+        repo.set_first_parent(config.first_parent);
+        let mut ws = Workspace::new(workdir, &config.scope, config_path.clone(), ignore_queue)?;
+        if quiet {
+            ws.set_event_sink(Arc::new(|_: &Event| ()));
         }
-        Some((commit, _)) => {
-            println!("Found new state to deploy - trigger commit {}", commit);
+        let mut envs: Vec<_> = config
+            .environments
+            .values()
+            .filter(|env| only.is_empty() || only.contains(&env.name.as_str()))
+            .collect();
+        envs.sort_by(|a, b| a.name.cmp(&b.name));
+        for env in envs {
+            let gate = match gates.as_ref().map(|gates| gates.get_gate(&env.name)) {
+                Some(Ok(gate)) => gate,
+                // Keep checking the other environments
+                Some(Err(e)) => {
+                    eprintln!("Skipping {}/{}: {}", config.scope, env.name, e);
+                    continue;
+                }
+                None => None,
+            };
+            repo.set_gate(gate)?;
+            match ws.check_in(&repo, env) {
+                Ok(Some(report)) => triggers.push(Trigger {
+                    deployment: config.scope.clone(),
+                    environment: env.name.clone(),
+                    trigger: report.trigger,
+                    config: config_path.clone(),
+                }),
+                Ok(None) => (),
+                // Nothing to deploy until the previous environment has been deployed
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<CeplerError>(),
+                        Some(CeplerError::UpstreamNotDeployed(_))
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(triggers)
+}
+
+fn watch(
+    matches: &ArgMatches,
+    root_matches: &ArgMatches,
+    workdir: &Path,
+    ignore_queue: bool,
+) -> Result<()> {
+    let interval = matches
+        .value_of("INTERVAL")
+        .unwrap()
+        .parse()
+        .map(Duration::from_secs)
+        .context("--interval must be a number of seconds")?;
+    let only: Vec<&str> = matches
+        .values_of("ENVIRONMENT")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+    let once = matches.is_present("ONCE");
+    let mut last_seen: HashMap<(String, String), String> = HashMap::new();
+    loop {
+        match poll(root_matches, workdir, ignore_queue, &only) {
+            Ok(triggers) => {
+                let mut seen = HashMap::new();
+                for trigger in triggers {
+                    let key = (trigger.deployment.clone(), trigger.environment.clone());
+                    if last_seen.get(&key) != Some(&trigger.trigger) {
+                        if let Err(e) = emit(matches.value_of("EXEC"), &trigger) {
+                            eprintln!("{:#}", e);
+                            continue;
+                        }
+                    }
+                    seen.insert(key, trigger.trigger);
+                }
+                last_seen = seen;
+            }
+            Err(e) if !once => eprintln!("Couldn't check for new triggers: {:#}", e),
+            Err(e) => return Err(e),
+        }
+        if once {
+            return Ok(());
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Pulls the repository if it was cloned and checks the environments in `only`.
+fn poll(
+    matches: &ArgMatches,
+    workdir: &Path,
+    ignore_queue: bool,
+    only: &[&str],
+) -> Result<Vec<Trigger>> {
+    if let Some(conf) = clone_config_from_matches(matches)? {
+        Repo::open(workdir, None)?.pull(conf)?;
+    }
+    let configs = configs_from_matches(matches, workdir)?;
+    let gates = gates_from_matches(matches, workdir)?;
+    find_triggers(matches, workdir, configs, gates, ignore_queue, only, true)
+}
+
+fn lead_time(matches: &ArgMatches, root_matches: &ArgMatches, workdir: &Path) -> Result<()> {
+    let only: Vec<&str> = matches
+        .values_of("ENVIRONMENT")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+    let configs = configs_from_matches(root_matches, workdir)?;
+    let lead_times = report::lead_times(
+        workdir,
+        &configs,
+        &only,
+        &credentials_from_matches(root_matches),
+        known_hosts_from_matches(root_matches)?,
+    )?;
+    if matches.value_of("FORMAT") == Some("json") {
+        println!("{}", serde_json::to_string_pretty(&lead_times)?);
+    } else {
+        print!("{}", report::to_csv(&lead_times));
+    }
+    Ok(())
+}
+
+fn serve(
+    matches: &ArgMatches,
+    root_matches: &ArgMatches,
+    workdir: &Path,
+    ignore_queue: bool,
+) -> Result<()> {
+    let interval = matches
+        .value_of("REFRESH")
+        .unwrap()
+        .parse()
+        .map(Duration::from_secs)
+        .context("--refresh must be a number of seconds")?;
+    serve::serve(
+        matches.value_of("LISTEN").unwrap(),
+        workdir,
+        ignore_queue,
+        interval,
+        || {
+            if let Some(conf) = clone_config_from_matches(root_matches)? {
+                Repo::open(workdir, None)?.pull(conf)?;
+            }
+            Ok(serve::Snapshot {
+                configs: configs_from_matches(root_matches, workdir)?,
+                gates: gates_from_matches(root_matches, workdir)?,
+                credentials: credentials_from_matches(root_matches),
+                known_hosts: known_hosts_from_matches(root_matches)?,
+            })
+        },
+    )
+}
+
+/// Prints `trigger` as a JSON line or passes it to `command` via the environment.
+fn emit(command: Option<&str>, trigger: &Trigger) -> Result<()> {
+    if let Some(command) = command {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("CEPLER_DEPLOYMENT", &trigger.deployment)
+            .env("CEPLER_ENVIRONMENT", &trigger.environment)
+            .env("CEPLER_TRIGGER", &trigger.trigger)
+            .env("CEPLER_CONF", &trigger.config)
+            .status()
+            .context(format!("Couldn't run '{}'", command))?;
+        if !status.success() {
+            return Err(anyhow!(
+                "'{}' failed for {}/{} ({})",
+                command,
+                trigger.deployment,
+                trigger.environment,
+                status
+            ));
+        }
+    } else {
+        println!("{}", serde_json::to_string(trigger)?);
+    }
+    Ok(())
+}
+
+fn diff(
+    matches: &ArgMatches,
+    root_matches: &ArgMatches,
+    workdir: &Path,
+    (config, config_path): (Config, String),
+    gates: Option<GatesConfig>,
+    ignore_queue: bool,
+) -> Result<()> {
+    let env = matches.value_of("ENVIRONMENT").unwrap();
+    let gate = if let Some(gates) = gates {
+        gates.get_gate(env)?
+    } else {
+        None
+    };
+    let ws = workspace(root_matches, workdir, &config, config_path, ignore_queue)?;
+    let env = config.environment(env)?;
+    if let Some(lock) = ws.get_lock(env) {
+        // The pending files are listed all the same
+        eprintln!(
+            "{}",
+            Event::EnvironmentLocked {
+                environment: env.name.clone(),
+                reason: lock.reason.clone(),
+            }
+        );
+    }
+    if matches.is_present("CONTENT") {
+        for diff in ws.diff_content(env, gate)? {
+            print!("{}", diff);
+        }
+    } else if let Some(report) = ws.pending_diff(env, gate)? {
+        for diff in report.diffs {
+            println!("{}", diff.ident.name());
         }
     }
     Ok(())
@@ -163,6 +581,8 @@ fn check(
 
 fn ls(
     matches: &ArgMatches,
+    root_matches: &ArgMatches,
+    workdir: &Path,
     (config, config_path): (Config, String),
     gates: Option<GatesConfig>,
     ignore_queue: bool,
@@ -173,11 +593,8 @@ fn ls(
     } else {
         None
     };
-    let ws = Workspace::new(&config.scope, config_path.clone(), ignore_queue)?;
-    let env = config.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config_path
-    ))?;
+    let ws = workspace(root_matches, workdir, &config, config_path, ignore_queue)?;
+    let env = config.environment(env)?;
     for path in ws.ls(env, gate)? {
         println!("{}", path);
     }
@@ -185,6 +602,8 @@ fn ls(
 }
 fn prepare(
     matches: &ArgMatches,
+    root_matches: &ArgMatches,
+    workdir: &Path,
     config: (Config, String),
     gates: Option<GatesConfig>,
     ignore_queue: bool,
@@ -199,31 +618,41 @@ fn prepare(
     } else {
         None
     };
-    let env = config.0.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config.1
-    ))?;
-    let ws = Workspace::new(&config.0.scope, config.1, ignore_queue)?;
-    ws.prepare(env, gate, force_clean)?;
+    let env = config.0.environment(env)?;
+    let only = only_from_matches(matches, env)?;
+    let mut ws = workspace(root_matches, workdir, &config.0, config.1, ignore_queue)?;
+    if matches.is_present("FORCE") {
+        ws.ignore_lock();
+    }
+    if let Some(trigger) = matches.value_of("FROM_UPSTREAM_STATE") {
+        ws.target_upstream_state(env, trigger)?;
+    }
+    ws.prepare(env, gate, force_clean, &only)?;
     Ok(())
 }
-fn reproduce(matches: &ArgMatches, config: (Config, String)) -> Result<()> {
+fn reproduce(
+    matches: &ArgMatches,
+    root_matches: &ArgMatches,
+    workdir: &Path,
+    config: (Config, String),
+) -> Result<()> {
     let env = matches.value_of("ENVIRONMENT").unwrap();
     let force_clean: bool = matches.is_present("FORCE_CLEAN");
     if force_clean {
         println!("WARNING removing all non-cepler specified files");
     }
-    let env = config.0.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config.1
-    ))?;
-    let ws = Workspace::new(&config.0.scope, config.1, false)?;
-    ws.reproduce(env, force_clean)?;
+    let at = matches.value_of("AT");
+    let output = matches.value_of("OUTPUT").map(Path::new);
+    let env = config.0.environment(env)?;
+    let ws = workspace(root_matches, workdir, &config.0, config.1, false)?;
+    ws.reproduce(env, force_clean, at, output)?;
     Ok(())
 }
 
 fn record(
     matches: &ArgMatches,
+    root_matches: &ArgMatches,
+    workdir: &Path,
     config: (Config, String),
     gates: Option<GatesConfig>,
     ignore_queue: bool,
@@ -242,33 +671,120 @@ fn record(
             url: matches.value_of("GIT_URL").unwrap().to_string(),
             branch: matches.value_of("GIT_BRANCH").unwrap().to_string(),
             gates_branch: None,
-            private_key: matches.value_of("GIT_PRIVATE_KEY").unwrap().to_string(),
+            credentials: credentials_from_matches(matches),
+            known_hosts: known_hosts_from_matches(matches)?,
+            depth: None,
+            blobless: false,
             dir: String::new(),
         })
     } else {
         None
     };
-    let env = config.0.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config.1
-    ))?;
-    let mut ws = Workspace::new(&config.0.scope, config.1, ignore_queue)?;
-    ws.record_env(env, gate, commit, reset, git_config)?;
+    let env = config.0.environment(env)?;
+    let only = only_from_matches(matches, env)?;
+    let mut ws = workspace(root_matches, workdir, &config.0, config.1, ignore_queue)?;
+    if let Some(git_config) = git_config.as_ref() {
+        // Fetch with the credentials given for pushing
+        ws.set_credentials(
+            git_config.credentials.clone(),
+            git_config.known_hosts.clone(),
+        );
+    }
+    ws.set_commit_config(config.0.commit.clone())?;
+    if matches.is_present("FORCE") {
+        ws.ignore_lock();
+    }
+    if let Some(trigger) = matches.value_of("FROM_UPSTREAM_STATE") {
+        ws.target_upstream_state(env, trigger)?;
+    }
+    ws.record_env(env, gate, commit, reset, git_config, &only)?;
     Ok(())
 }
 
-fn latest(matches: &ArgMatches, (config, config_file): (Config, String)) -> Result<()> {
+fn latest(
+    matches: &ArgMatches,
+    workdir: &Path,
+    (config, config_file): (Config, String),
+) -> Result<()> {
     let env = matches.value_of("ENVIRONMENT").unwrap();
-    let db = Database::open(&config.scope, &config_file, false)?;
-    if let Some(env) = db.get_current_state(env) {
-        println!("{}", env.head_commit.clone().inner());
+    let db = Database::open(workdir, &config.scope, &config_file, false)?;
+    let state = db
+        .get_current_state(env)
+        .ok_or_else(|| CeplerError::NotDeployed(env.to_string()))?;
+    println!("{}", state.head_commit.clone().inner());
+    Ok(())
+}
+
+fn lock(
+    matches: &ArgMatches,
+    workdir: &Path,
+    (config, config_path): (Config, String),
+) -> Result<()> {
+    let env = matches.value_of("ENVIRONMENT").unwrap();
+    let reason = matches.value_of("REASON").unwrap().to_string();
+    let commit = !matches.is_present("NO_COMMIT");
+    let env = config.environment(env)?;
+    let mut ws = Workspace::new(workdir, &config.scope, config_path, false)?;
+    ws.set_commit_config(config.commit.clone())?;
+    ws.lock(env, reason, commit)?;
+    Ok(())
+}
+
+fn unlock(
+    matches: &ArgMatches,
+    workdir: &Path,
+    (config, config_path): (Config, String),
+) -> Result<()> {
+    let env = matches.value_of("ENVIRONMENT").unwrap();
+    let commit = !matches.is_present("NO_COMMIT");
+    let env = config.environment(env)?;
+    let mut ws = Workspace::new(workdir, &config.scope, config_path, false)?;
+    ws.set_commit_config(config.commit.clone())?;
+    ws.unlock(env, commit)?;
+    Ok(())
+}
+
+fn status(
+    matches: &ArgMatches,
+    workdir: &Path,
+    (config, config_path): (Config, String),
+) -> Result<()> {
+    let ws = Workspace::new(workdir, &config.scope, config_path.clone(), false)?;
+    let mut envs: Vec<_> = if let Some(env) = matches.value_of("ENVIRONMENT") {
+        vec![config.environment(env)?]
     } else {
-        eprintln!("Environment '{}' not deployed!", env);
-        std::process::exit(1);
+        config.environments.values().collect()
+    };
+    envs.sort_by(|a, b| a.name.cmp(&b.name));
+    for env in envs {
+        match ws.get_current_state(env) {
+            Some(state) => println!("{}: trigger {}", env.name, state.head_commit),
+            None => println!("{}: not deployed", env.name),
+        }
+        if let Some(lock) = ws.get_lock(env) {
+            println!("  locked: {}", lock.reason);
+        }
     }
     Ok(())
 }
 
+/// Opens a workspace whose repositories access the remote with the credentials given on the command line.
+fn workspace(
+    root_matches: &ArgMatches,
+    workdir: &Path,
+    config: &Config,
+    config_path: String,
+    ignore_queue: bool,
+) -> Result<Workspace> {
+    let mut ws = Workspace::new(workdir, &config.scope, config_path, ignore_queue)?;
+    ws.set_first_parent(config.first_parent);
+    ws.set_credentials(
+        credentials_from_matches(root_matches),
+        known_hosts_from_matches(root_matches)?,
+    );
+    Ok(ws)
+}
+
 fn concourse_check() -> Result<()> {
     concourse::check::exec()
 }
@@ -283,16 +799,154 @@ fn concourse_out(matches: &ArgMatches) -> Result<()> {
     concourse::ci_out::exec(origin)
 }
 
-fn conf_from_matches(matches: &ArgMatches) -> Result<(Config, String)> {
-    let file_name = matches.value_of("CONFIG_FILE").unwrap();
-    Ok((Config::from_file(file_name)?, file_name.to_string()))
+fn conf_from_matches(matches: &ArgMatches, workdir: &Path) -> Result<(Config, String)> {
+    let mut files = matches.values_of("CONFIG_FILE").unwrap();
+    let file = files.next().unwrap();
+    if files.next().is_some() {
+        return Err(anyhow!(
+            "Only 'check --all', 'watch', 'serve', 'metrics' and 'report' accept more than one config"
+        ));
+    }
+    let file_name = repo_path(workdir, file)?;
+    let mut config = Config::from_file(workdir, &file_name)?;
+    config.first_parent |= matches.is_present("FIRST_PARENT");
+    Ok((config, file_name))
+}
+
+/// Returns the configs given via `-c`. If none were given every `cepler*.yml`
+/// committed below the current directory that defines environments is returned.
+fn configs_from_matches(matches: &ArgMatches, workdir: &Path) -> Result<Vec<(Config, String)>> {
+    let mut file_names = Vec::new();
+    if matches.occurrences_of("CONFIG_FILE") > 0 || std::env::var_os("CEPLER_CONF").is_some() {
+        for file in matches.values_of("CONFIG_FILE").into_iter().flatten() {
+            file_names.push(repo_path(workdir, file)?);
+        }
+    } else {
+        let cwd = std::env::current_dir()?.canonicalize()?;
+        let prefix = cwd
+            .strip_prefix(workdir.canonicalize()?)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let repo = Repo::open(workdir, None)?;
+        repo.all_files(repo.head_commit_summary()?.0, |_, _, path| {
+            let name = path.file_name().and_then(|name| name.to_str());
+            if matches!(name, Some(name) if name.starts_with("cepler") && name.ends_with(".yml"))
+                && path.starts_with(&prefix)
+                && defines_environments(&workdir.join(path))
+            {
+                file_names.push(path.display().to_string());
+            }
+            Ok(())
+        })?;
+        file_names.sort();
+    }
+    let configs: Vec<(Config, String)> = file_names
+        .into_iter()
+        .map(|file_name| {
+            let mut config = Config::from_file(workdir, &file_name)?;
+            config.first_parent |= matches.is_present("FIRST_PARENT");
+            Ok((config, file_name))
+        })
+        .collect::<Result<_>>()?;
+    // Environments are identified by deployment and name across configs
+    let mut scopes: HashMap<&str, &str> = HashMap::new();
+    for (config, file_name) in configs.iter() {
+        if let Some(other) = scopes.insert(&config.scope, file_name) {
+            return Err(CeplerError::ConfigInvalid(format!(
+                "Configs '{}' and '{}' both use the deployment '{}'",
+                other, file_name, config.scope
+            ))
+            .into());
+        }
+    }
+    Ok(configs)
+}
+
+/// Distinguishes cepler configs from other files following the same naming scheme (eg. gate files).
+fn defines_environments(path: &Path) -> bool {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_yaml::from_slice::<serde_yaml::Value>(&bytes).ok())
+        .map(|value| value.get("environments").is_some())
+        .unwrap_or(false)
+}
+
+/// Resolves `file` to a path relative to `workdir`.
+/// Paths pointing to an existing file inside the repository are taken relative to the
+/// current directory, all other paths relative to the root of the repository.
+/// Falling back to the root is reported when running from a subdirectory.
+fn repo_path(workdir: &Path, file: &str) -> Result<String> {
+    let cwd = std::env::current_dir()?;
+    let candidate = cwd.join(file);
+    if candidate.is_file() {
+        let candidate = candidate.canonicalize()?;
+        if let Ok(path) = candidate.strip_prefix(workdir.canonicalize()?) {
+            return Ok(path.display().to_string());
+        }
+    }
+    if Path::new(file).is_relative() && cwd.canonicalize()? != workdir.canonicalize()? {
+        eprintln!(
+            "'{}' doesn't exist in the current directory, using '{}'",
+            file,
+            workdir.join(file).display()
+        );
+    }
+    Ok(file.to_string())
+}
+
+fn only_from_matches(matches: &ArgMatches, env: &EnvironmentConfig) -> Result<Vec<glob::Pattern>> {
+    let only: Vec<String> = matches
+        .values_of("ONLY")
+        .map(|values| values.map(|only| only.to_string()).collect())
+        .unwrap_or_default();
+    env.only_patterns(&only)
+}
+
+fn credentials_from_matches(matches: &ArgMatches) -> GitCredentials {
+    let value = |name| matches.value_of(name).map(|v| v.to_string());
+    GitCredentials {
+        username: value("GIT_USERNAME"),
+        password: value("GIT_PASSWORD"),
+        private_key: value("GIT_PRIVATE_KEY"),
+        private_key_file: value("GIT_PRIVATE_KEY_FILE"),
+        passphrase: value("GIT_PASSPHRASE"),
+    }
+}
+
+fn known_hosts_from_matches(matches: &ArgMatches) -> Result<Option<String>> {
+    matches
+        .value_of("KNOWN_HOSTS")
+        .map(|file| {
+            std::fs::read_to_string(file).context(format!("Couldn't read known_hosts '{}'", file))
+        })
+        .transpose()
+}
+
+fn clone_config_from_matches(matches: &ArgMatches) -> Result<Option<GitConfig>> {
+    let dir = match matches.value_of("CLONE_DIR") {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    Ok(Some(GitConfig {
+        url: matches.value_of("GIT_URL").unwrap().to_string(),
+        branch: matches.value_of("GIT_BRANCH").unwrap().to_string(),
+        gates_branch: matches.value_of("GATES_BRANCH").map(|b| b.to_string()),
+        credentials: credentials_from_matches(matches),
+        known_hosts: known_hosts_from_matches(matches)?,
+        depth: matches
+            .value_of("CLONE_DEPTH")
+            .map(|depth| depth.parse().context("--clone-depth must be a number"))
+            .transpose()?,
+        blobless: matches.is_present("CLONE_BLOBLESS"),
+        dir: dir.to_string(),
+    }))
 }
 
 #[allow(clippy::redundant_closure)]
-fn gates_from_matches(matches: &ArgMatches) -> Result<Option<GatesConfig>> {
+fn gates_from_matches(matches: &ArgMatches, workdir: &Path) -> Result<Option<GatesConfig>> {
     let file_name = matches.value_of("GATES_FILE");
     if let Some(branch) = matches.value_of("GATES_BRANCH") {
-        match Repo::open(None)?.get_file_from_branch(
+        match Repo::open(workdir, None)?.get_file_from_branch(
             branch,
             Path::new(file_name.unwrap()),
             |bytes| GatesConfig::from_reader(bytes),
@@ -302,7 +956,9 @@ fn gates_from_matches(matches: &ArgMatches) -> Result<Option<GatesConfig>> {
             err => err,
         }
     } else if let Some(f) = file_name {
-        Ok(Some(GatesConfig::from_file(f)?))
+        Ok(Some(GatesConfig::from_file(
+            workdir.join(repo_path(workdir, f)?),
+        )?))
     } else {
         Ok(None)
     }
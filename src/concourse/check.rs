@@ -1,5 +1,9 @@
 use super::*;
-use crate::{config::Config, workspace::Workspace};
+use crate::{
+    config::{Config, EnvironmentConfig},
+    workspace::{CheckReport, Workspace},
+};
+use sha2::{Digest, Sha256};
 use std::{
     env,
     fs::File,
@@ -31,16 +35,19 @@ pub fn exec() -> Result<()> {
         "{}/cepler-repo-cache",
         env::var(TMPDIR).unwrap_or_else(|_| "/tmp".to_string())
     );
-    let mut file = File::create(&format!(
+    let mut file = File::create(format!(
         "{}/cepler-check-input",
         env::var(TMPDIR).unwrap_or_else(|_| "/tmp".to_string())
     ))?;
     file.write_all(&serde_json::to_vec(&resource)?)?;
     let conf = GitConfig {
-        url: source.uri,
+        credentials: source.credentials(),
+        known_hosts: source.known_hosts.clone(),
+        depth: source.depth,
+        blobless: source.blobless,
+        url: source.uri.clone(),
         branch: source.branch.clone(),
         gates_branch: source.gates_branch.clone(),
-        private_key: source.private_key,
         dir: clone_dir.clone(),
     };
     let path = path::Path::new(&clone_dir);
@@ -52,7 +59,7 @@ pub fn exec() -> Result<()> {
     } else {
         eprintln!("Pulling latest state");
         std::env::set_current_dir(path)?;
-        let repo = Repo::open(None)?;
+        let repo = Repo::open(Path::new("."), None)?;
         repo.pull(conf)?;
         repo
     };
@@ -62,15 +69,19 @@ pub fn exec() -> Result<()> {
         source.branch, hash, summary
     );
 
-    let config = Config::from_file(&source.config)?;
-    let ws = Workspace::new(&config.scope, source.config.clone(), source.ignore_queue)?;
+    let config = Config::from_file(".", &source.config)?;
+    let mut ws = Workspace::new(
+        Path::new("."),
+        &config.scope,
+        source.config.clone(),
+        source.ignore_queue,
+    )?;
+    ws.set_first_parent(config.first_parent);
+    ws.set_credentials(source.credentials(), source.known_hosts.clone());
     let environment = source
         .environment
         .ok_or_else(|| anyhow!("Environment not specified in source"))?;
-    let env = config.environments.get(&environment).context(format!(
-        "Environment '{}' not found in config '{}'",
-        environment, source.config
-    ))?;
+    let env = config.environment(&environment)?;
     eprintln!("Checking equivalence with last deployed state...");
     let mut res = Vec::new();
     let gate = get_gate(
@@ -79,22 +90,25 @@ pub fn exec() -> Result<()> {
         &environment,
         &repo,
     )?;
-    match (version, ws.check(env, gate)?) {
-        (None, Some((trigger, _))) => {
+    let next = ws.check(env, gate)?.map(|report| Version {
+        pending: pending_digest(&ws, env, &report),
+        trigger: report.trigger,
+    });
+    match (version, next) {
+        (None, Some(next)) => {
             eprintln!("Found new state to deploy");
-            res.push(Version { trigger })
+            res.push(next)
         }
-        (Some(last), Some((trigger, _))) if last.trigger != trigger => {
+        (Some(last), Some(next)) if last != next => {
             eprintln!("Found new state to deploy");
             res.push(last);
-            res.push(Version { trigger })
+            res.push(next)
         }
-        (Some(last), ret) => {
-            match ret {
-                Some((trigger, _)) if last.trigger == trigger => {
-                    eprintln!("Last trigger is still up to date")
-                }
-                _ => eprintln!("Nothing new to deploy"),
+        (Some(last), next) => {
+            if next.is_some() {
+                eprintln!("Last trigger is still up to date")
+            } else {
+                eprintln!("Nothing new to deploy")
             }
             res.push(last);
         }
@@ -105,3 +119,24 @@ pub fn exec() -> Result<()> {
     println!("{}", serde_json::to_string(&res)?);
     Ok(())
 }
+
+/// When `trigger` was already recorded with `--only` the remaining files would
+/// yield the same version again, so they are told apart by a digest of their states.
+fn pending_digest(ws: &Workspace, env: &EnvironmentConfig, report: &CheckReport) -> Option<String> {
+    let current = ws.get_current_state(env)?;
+    if current.head_commit.clone().inner() != report.trigger {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    for diff in report.diffs.iter() {
+        hasher.update(diff.ident.clone().inner().as_bytes());
+        hasher.update(serde_json::to_vec(&diff.current_state).ok()?);
+    }
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
@@ -1,5 +1,8 @@
 use super::*;
-use crate::workspace::Workspace;
+use crate::{
+    error::CeplerError,
+    workspace::{CheckReport, Workspace},
+};
 use glob::*;
 use std::{io, path::Path};
 
@@ -9,12 +12,15 @@ pub fn exec(destination: &str) -> Result<()> {
         source, version, ..
     }: ResourceConfig = serde_json::from_reader(io::stdin()).context("Deserializing stdin")?;
     eprintln!("Cloning repo to '{}'", destination);
-    let version = version.expect("No version specified");
+    let version = version.context("No version specified")?;
     let conf = GitConfig {
-        url: source.uri,
+        credentials: source.credentials(),
+        known_hosts: source.known_hosts.clone(),
+        depth: source.depth,
+        blobless: source.blobless,
+        url: source.uri.clone(),
         branch: source.branch.clone(),
         gates_branch: source.gates_branch.clone(),
-        private_key: source.private_key,
         dir: destination.to_string(),
     };
 
@@ -27,18 +33,22 @@ pub fn exec(destination: &str) -> Result<()> {
         source.branch, hash, summary
     );
 
-    let config = Config::from_file(&source.config)?;
-    let ws = Workspace::new(&config.scope, source.config, source.ignore_queue)?;
+    let config = Config::from_file(".", &source.config)?;
+    let mut ws = Workspace::new(
+        Path::new("."),
+        &config.scope,
+        source.config.clone(),
+        source.ignore_queue,
+    )?;
+    ws.set_first_parent(config.first_parent);
+    ws.set_credentials(source.credentials(), source.known_hosts.clone());
     let environment = if let Some(environment) = source.environment {
         environment
     } else {
         eprintln!("No environment specified... providing an empty dir");
         return empty_repo(version);
     };
-    let env = config
-        .environments
-        .get(&environment)
-        .context(format!("Environment '{}' not found in config", environment))?;
+    let env = config.environment(&environment)?;
     eprintln!(
         "Checking if we can prepare deployment at trigger '{}'",
         version.trigger
@@ -50,10 +60,14 @@ pub fn exec(destination: &str) -> Result<()> {
         &environment,
         &repo,
     )?;
-    let (trigger, diff) = match ws.check(env, gate.clone())? {
-        Some((trigger, _)) if &trigger != wanted_trigger => {
-            eprintln!("Trigger is out of sync.");
-            std::process::exit(1);
+    let CheckReport { trigger, diffs } = match ws.check(env, gate.clone())? {
+        Some(CheckReport { trigger, .. }) if &trigger != wanted_trigger => {
+            return Err(CeplerError::TriggerOutOfSync {
+                environment,
+                wanted: wanted_trigger.clone(),
+                found: trigger,
+            }
+            .into());
         }
         None => {
             eprintln!("Nothing new to deploy... providing an empty dir");
@@ -62,7 +76,7 @@ pub fn exec(destination: &str) -> Result<()> {
         Some(ret) => ret,
     };
     eprintln!("Preparing the workspace");
-    ws.prepare(env, gate, true)?;
+    ws.prepare(env, gate, true, &[])?;
 
     std::fs::write(".git/cepler_environment", &environment)
         .context("Couldn't create file '.git/cepler_environment'")?;
@@ -73,7 +87,7 @@ pub fn exec(destination: &str) -> Result<()> {
         "{}",
         serde_json::to_string(&ResourceData {
             version,
-            metadata: diff
+            metadata: diffs
                 .into_iter()
                 .map(|diff| DiffElem {
                     name: diff.ident.inner(),
@@ -20,16 +20,40 @@ struct Source {
     branch: String,
     gates_branch: Option<String>,
     gates_file: Option<String>,
-    private_key: String,
+    private_key: Option<String>,
+    private_key_passphrase: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    /// Content of a known_hosts file to verify ssh remotes against.
+    known_hosts: Option<String>,
+    /// Number of commits to clone. History is deepened on demand.
+    depth: Option<u32>,
+    #[serde(default = "bool::default")]
+    blobless: bool,
     environment: Option<String>,
     #[serde(default = "bool::default")]
     ignore_queue: bool,
     #[serde(default = "default_config_path")]
     config: String,
 }
-#[derive(Clone, Debug, Deserialize, Serialize)]
+impl Source {
+    fn credentials(&self) -> GitCredentials {
+        GitCredentials {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            private_key: self.private_key.clone(),
+            private_key_file: None,
+            passphrase: self.private_key_passphrase.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 struct Version {
     trigger: String,
+    /// Digest of the files still pending after `trigger` was recorded with `--only`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -1,3 +1,4 @@
+use super::error::CeplerError;
 use anyhow::*;
 use glob::*;
 use serde::Deserialize;
@@ -19,31 +20,208 @@ pub struct Config {
     #[serde(default = "default_scope")]
     #[serde(rename = "deployment")]
     pub scope: String,
+    /// Resolve the globs of all environments relative to the directory containing the config.
+    #[serde(default)]
+    pub relative_paths: bool,
+    /// Only follow the first parent of merge commits when looking for the
+    /// trigger and the commit that last changed a file.
+    #[serde(default)]
+    pub first_parent: bool,
+    /// Identity and signing key of the commits cepler creates.
+    #[serde(default)]
+    pub commit: CommitConfig,
     pub environments: HashMap<String, EnvironmentConfig>,
+    #[serde(skip)]
+    path: String,
 }
 
 impl Config {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = File::open(path).context("Couldn't open config file")?;
+    /// Reads the config at `path_to_config` relative to the root of the repository at `repo_dir`.
+    /// Patterns of `relative_paths` configs are resolved against the directory of the config.
+    pub fn from_file<P: AsRef<Path>>(repo_dir: P, path_to_config: &str) -> Result<Self> {
+        let path = repo_dir.as_ref().join(path_to_config);
+        let path = path.as_path();
+        let file = File::open(path).map_err(|e| {
+            CeplerError::ConfigInvalid(format!(
+                "Couldn't open config file '{}': {}",
+                path.display(),
+                e
+            ))
+        })?;
         let reader = BufReader::new(file);
 
-        Self::from_reader(reader)
+        let mut config = Self::from_reader(reader)?.with_path_to_config(path_to_config);
+        config.path = path.display().to_string();
+        Ok(config)
     }
 
     pub fn from_reader(reader: impl Read) -> Result<Self> {
-        let mut config: Config = serde_yaml::from_reader(reader)?;
+        let mut config: Config = serde_yaml::from_reader(reader)
+            .map_err(|e| CeplerError::ConfigInvalid(e.to_string()))?;
         let all_environments: HashSet<String> = config.environments.keys().cloned().collect();
-        for (name, mut env) in config.environments.iter_mut() {
+        for (name, env) in config.environments.iter_mut() {
             env.name = name.clone();
             if let Some(previous) = env.propagated_from.as_ref() {
                 if !all_environments.contains(previous) {
-                    return Err(anyhow!("Previous environment '{}' not defined", previous));
+                    return Err(CeplerError::ConfigInvalid(format!(
+                        "Previous environment '{}' not defined",
+                        previous
+                    ))
+                    .into());
                 }
             }
         }
+        if let Some(template) = config.commit.message_template.as_ref() {
+            let values: Vec<_> = MESSAGE_PLACEHOLDERS.iter().map(|p| (*p, "")).collect();
+            render_template(template, &values)?;
+        }
 
         Ok(config)
     }
+
+    /// Sets the location of the config relative to the repository root.
+    /// Only has an effect if `relative_paths` is enabled.
+    pub(crate) fn with_path_to_config(mut self, path_to_config: &str) -> Self {
+        if self.relative_paths {
+            let base_dir = Path::new(path_to_config)
+                .parent()
+                .map(|dir| dir.display().to_string())
+                .unwrap_or_default()
+                .trim_start_matches("./")
+                .to_string();
+            for env in self.environments.values_mut() {
+                env.base_dir = base_dir.clone();
+            }
+        }
+        self
+    }
+
+    pub fn environment(&self, name: &str) -> Result<&EnvironmentConfig> {
+        self.environments.get(name).ok_or_else(|| {
+            CeplerError::EnvironmentUnknown {
+                environment: name.to_string(),
+                config: self.path.clone(),
+            }
+            .into()
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CommitConfig {
+    pub author_name: Option<String>,
+    pub author_email: Option<String>,
+    /// GPG key id or path to the SSH key used to sign commits.
+    pub signing_key: Option<String>,
+    #[serde(default)]
+    pub signing_format: SigningFormat,
+    /// Template of state commit messages containing `{placeholder}`s.
+    pub message_template: Option<String>,
+}
+
+/// The placeholders that can be used in `commit.message_template`.
+pub const MESSAGE_PLACEHOLDERS: &[&str] = &[
+    "deployment",
+    "environment",
+    "trigger",
+    "trigger_short",
+    "changed_count",
+    "changes",
+];
+
+impl CommitConfig {
+    /// Overrides the settings with `CEPLER_AUTHOR_NAME`, `CEPLER_AUTHOR_EMAIL`,
+    /// `CEPLER_SIGNING_KEY` and `CEPLER_SIGNING_FORMAT` if they are set.
+    pub fn with_env(mut self) -> Result<Self> {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        if let Some(name) = var("CEPLER_AUTHOR_NAME") {
+            self.author_name = Some(name);
+        }
+        if let Some(email) = var("CEPLER_AUTHOR_EMAIL") {
+            self.author_email = Some(email);
+        }
+        if let Some(key) = var("CEPLER_SIGNING_KEY") {
+            self.signing_key = Some(key);
+        }
+        if let Some(format) = var("CEPLER_SIGNING_FORMAT") {
+            self.signing_format = serde_yaml::from_str(&format).map_err(|_| {
+                CeplerError::ConfigInvalid(format!(
+                    "Unknown signing format '{}' - expected 'openpgp' or 'ssh'",
+                    format
+                ))
+            })?;
+        }
+        Ok(self)
+    }
+
+    /// Renders the state commit message if a template is configured.
+    pub fn state_message(&self, values: &[(&str, &str)]) -> Result<Option<String>> {
+        self.message_template
+            .as_ref()
+            .map(|template| render_template(template, values))
+            .transpose()
+    }
+}
+
+/// Replaces every `{name}` in `template` with the value of `name`.
+/// `{{` and `}}` render a literal `{` and `}`.
+fn render_template(template: &str, values: &[(&str, &str)]) -> Result<String> {
+    let mut ret = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        ret.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if let Some(tail) = tail.strip_prefix("{{") {
+            ret.push('{');
+            rest = tail;
+            continue;
+        }
+        if let Some(tail) = tail.strip_prefix("}}") {
+            ret.push('}');
+            rest = tail;
+            continue;
+        }
+        if tail.starts_with('}') {
+            return Err(CeplerError::ConfigInvalid(format!(
+                "Unmatched '}}' in message template '{}' - use '}}}}' for a literal '}}'",
+                template
+            ))
+            .into());
+        }
+        let (name, tail) = tail[1..].split_once('}').ok_or_else(|| {
+            CeplerError::ConfigInvalid(format!(
+                "Unclosed '{{' in message template '{}' - use '{{{{' for a literal '{{'",
+                template
+            ))
+        })?;
+        let value = values
+            .iter()
+            .find(|(placeholder, _)| *placeholder == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| {
+                CeplerError::ConfigInvalid(format!(
+                    "Unknown placeholder '{{{}}}' in message template - expected one of {}",
+                    name,
+                    MESSAGE_PLACEHOLDERS
+                        .iter()
+                        .map(|p| format!("{{{}}}", p))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            })?;
+        ret.push_str(value);
+        rest = tail;
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningFormat {
+    #[default]
+    Openpgp,
+    Ssh,
 }
 
 #[derive(Debug)]
@@ -53,23 +231,30 @@ pub struct GatesConfig {
 
 impl GatesConfig {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = File::open(path).context("Couldn't open gates file")?;
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| {
+            CeplerError::GateInvalid(format!(
+                "Couldn't open gates file '{}': {}",
+                path.display(),
+                e
+            ))
+        })?;
         let reader = BufReader::new(file);
 
         Self::from_reader(reader)
     }
 
     pub fn from_reader(reader: impl Read) -> Result<Self> {
-        let gates: HashMap<String, String> = serde_yaml::from_reader(reader)?;
+        let gates: HashMap<String, String> =
+            serde_yaml::from_reader(reader).map_err(|e| CeplerError::GateInvalid(e.to_string()))?;
 
         Ok(GatesConfig { gates })
     }
 
-    pub fn get_gate(mut self, env: &str) -> Result<Option<String>> {
-        let gate = self
-            .gates
-            .remove(env)
-            .context("Environment is missing in gates file")?;
+    pub fn get_gate(&self, env: &str) -> Result<Option<String>> {
+        let gate = self.gates.get(env).cloned().ok_or_else(|| {
+            CeplerError::GateInvalid(format!("Environment '{}' is missing in gates file", env))
+        })?;
         if gate == "HEAD" {
             Ok(None)
         } else {
@@ -92,6 +277,8 @@ pub struct EnvironmentConfig {
     #[serde(rename = "latest")]
     #[serde(default)]
     head_files: Vec<String>,
+    #[serde(skip)]
+    base_dir: String,
 }
 
 impl EnvironmentConfig {
@@ -99,41 +286,81 @@ impl EnvironmentConfig {
         self.propagated_from.as_ref()
     }
 
-    pub fn propagated_file_patterns(&self) -> impl Iterator<Item = glob::Pattern> {
-        self.propagated_files
-            .to_vec()
+    pub fn propagated_file_patterns(&self) -> Result<Vec<glob::Pattern>> {
+        patterns(&self.name, &self.resolve(&self.propagated_files))
+    }
+
+    /// Lists the propagated files present in `dir`, relative to `dir`.
+    pub fn propagated_files(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        files_in(dir, &self.resolve(&self.propagated_files))
+    }
+
+    /// Lists the latest files present in `dir`, relative to `dir`.
+    pub fn head_files(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        files_in(dir, &self.resolve(&self.head_files))
+    }
+
+    pub fn head_file_patterns(&self) -> Result<Vec<glob::Pattern>> {
+        patterns(&self.name, &self.resolve(&self.head_files))
+    }
+
+    /// Resolves `--only` globs the same way as the globs of the environment.
+    pub fn only_patterns(&self, only: &[String]) -> Result<Vec<Pattern>> {
+        self.resolve(only)
             .into_iter()
-            .map(|path| glob::Pattern::new(&path).expect("Couldn't compile glob pattern"))
+            .map(|only| Pattern::new(&only).context(format!("Invalid --only glob '{}'", only)))
+            .collect()
     }
 
-    pub fn propagated_files(&self) -> impl Iterator<Item = PathBuf> {
-        let files: Vec<_> = self.propagated_files.to_vec();
+    fn resolve(&self, files: &[String]) -> Vec<String> {
+        if self.base_dir.is_empty() {
+            return files.to_vec();
+        }
+        let prefix = Pattern::escape(&self.base_dir);
         files
-            .into_iter()
-            .map(|file| glob(&file).expect("Couldn't resolve glob"))
-            .flatten()
-            .map(|res| res.expect("Couldn't list file"))
+            .iter()
+            .map(|file| format!("{}/{}", prefix, file.trim_start_matches("./")))
+            .collect()
     }
+}
 
-    pub fn head_file_patterns(&self) -> impl Iterator<Item = glob::Pattern> {
-        self.head_files
-            .to_vec()
-            .into_iter()
-            .map(|path| glob::Pattern::new(&path).expect("Couldn't compile glob pattern"))
+fn patterns(env: &str, files: &[String]) -> Result<Vec<Pattern>> {
+    files
+        .iter()
+        .map(|path| {
+            Pattern::new(path).map_err(|e| {
+                CeplerError::ConfigInvalid(format!(
+                    "Invalid glob '{}' in environment '{}': {}",
+                    path, env, e
+                ))
+                .into()
+            })
+        })
+        .collect()
+}
+
+fn files_in(dir: &Path, files: &[String]) -> Result<Vec<PathBuf>> {
+    let prefix = Pattern::escape(
+        dir.to_str()
+            .context(format!("Path '{}' is not valid utf-8", dir.display()))?,
+    );
+    let mut paths = Vec::new();
+    for file in files {
+        let pattern = format!("{}/{}", prefix.trim_end_matches('/'), file);
+        let entries = glob(&pattern)
+            .map_err(|e| CeplerError::ConfigInvalid(format!("Invalid glob '{}': {}", file, e)))?;
+        for entry in entries {
+            let path = entry.context("Couldn't list file")?;
+            paths.push(path.strip_prefix(dir)?.to_path_buf());
+        }
     }
+    Ok(paths)
 }
 
 pub fn default_scope() -> String {
     "default".to_string()
 }
 
-#[derive(Debug, Deserialize)]
-pub struct RepoConfig {
-    pub uri: String,
-    pub branch: String,
-    pub private_key: String,
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -151,6 +378,78 @@ mod test {
         assert!(
             conf.environments.get("testflight").unwrap().head_files == vec!["file.yml".to_string()]
         );
-        assert!(conf.scope == "default".to_string());
+        assert!(conf.scope == "default");
+    }
+
+    #[test]
+    fn relative_paths() {
+        let conf = r#"relative_paths: true
+environments:
+  testflight:
+    latest:
+    - "*.yml""#;
+
+        let conf = Config::from_reader(StringReader::new(conf))
+            .unwrap()
+            .with_path_to_config("team/cepler.yml");
+        let env = conf.environment("testflight").unwrap();
+        assert_eq!(env.head_file_patterns().unwrap()[0].as_str(), "team/*.yml");
+        let only = env.only_patterns(&["file.yml".to_string()]).unwrap();
+        assert_eq!(only[0].as_str(), "team/file.yml");
+    }
+
+    #[test]
+    fn render_message_template() {
+        let conf = r#"commit:
+  message_template: "Deployed {trigger_short} to {environment}\n\n{changes}"
+environments:
+  testflight:
+    latest:
+    - file.yml"#;
+
+        let conf = Config::from_reader(StringReader::new(conf)).unwrap();
+        let message = conf
+            .commit
+            .state_message(&[
+                ("trigger_short", "1234567"),
+                ("environment", "testflight"),
+                ("changes", "- file.yml"),
+            ])
+            .unwrap();
+        assert_eq!(
+            message.unwrap(),
+            "Deployed 1234567 to testflight\n\n- file.yml"
+        );
+
+        let err = render_template("{unknown}", &[]).unwrap_err();
+        assert_eq!(CeplerError::exit_code_of(&err), 10);
+
+        let message = render_template("{{{environment}}} }}", &[("environment", "testflight")]);
+        assert_eq!(message.unwrap(), "{testflight} }");
+        assert!(render_template("{environment", &[("environment", "")]).is_err());
+        assert!(render_template("environment}", &[]).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_message_template() {
+        let conf = r#"commit:
+  message_template: "Deployed {unknown}"
+environments:
+  testflight:
+    latest:
+    - file.yml"#;
+
+        let err = Config::from_reader(StringReader::new(conf)).unwrap_err();
+        assert_eq!(CeplerError::exit_code_of(&err), 10);
+    }
+
+    #[test]
+    fn unknown_previous_environment() {
+        let conf = r#"environments:
+  staging:
+    passed: testflight"#;
+
+        let err = Config::from_reader(StringReader::new(conf)).unwrap_err();
+        assert_eq!(CeplerError::exit_code_of(&err), 10);
     }
 }
@@ -1,4 +1,4 @@
-use super::{config::*, repo::*};
+use super::{config::*, error::*, repo::*};
 use anyhow::*;
 use glob::*;
 use serde::{Deserialize, Serialize};
@@ -7,12 +7,13 @@ use std::{
     fmt,
     fs::File,
     io::{BufReader, Read},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 pub struct Database {
     state: DbState,
     ignore_queue: bool,
+    workdir: PathBuf,
     pub state_dir: String,
 }
 
@@ -26,24 +27,51 @@ impl Database {
             match path.parent() {
                 Some(parent) if parent == Path::new("") => STATE_DIR.to_string(),
                 None => STATE_DIR.to_string(),
-                Some(parent) => format!("{}/{}", parent.to_str().unwrap(), STATE_DIR),
+                Some(parent) => format!("{}/{}", parent.display(), STATE_DIR),
             },
             scope
         )
     }
 
-    pub fn open(scope: &str, path_to_config: &str, ignore_queue: bool) -> Result<Self> {
+    pub fn open(
+        workdir: &Path,
+        scope: &str,
+        path_to_config: &str,
+        ignore_queue: bool,
+    ) -> Result<Self> {
         let mut state = DbState::default();
         let dir = Self::state_dir_from_config(scope, path_to_config);
-        if Path::new(&dir).is_dir() {
-            for path in glob(&format!("{}/*.state", dir))? {
+        let full_dir = workdir.join(&dir);
+        if full_dir.is_dir() {
+            let full_dir = Pattern::escape(
+                full_dir
+                    .to_str()
+                    .context(format!("Path '{}' is not valid utf-8", full_dir.display()))?,
+            );
+            for path in glob(&format!("{}/*.state", full_dir))? {
                 let path = path?;
                 if let Some(name) = path.as_path().file_stem() {
-                    let file = File::open(&path)?;
+                    let file =
+                        File::open(&path).context(format!("Couldn't open '{}'", path.display()))?;
                     let reader = BufReader::new(file);
                     state.environments.insert(
-                        name.to_str().expect("Convert name").to_string(),
-                        EnvironmentState::from_reader(reader)?,
+                        name.to_string_lossy().to_string(),
+                        EnvironmentState::from_reader(reader)
+                            .context(format!("Couldn't read '{}'", path.display()))?,
+                    );
+                }
+            }
+            for path in glob(&format!("{}/*.lock", full_dir))? {
+                let path = path?;
+                if let Some(name) = path.as_path().file_stem() {
+                    let file =
+                        File::open(&path).context(format!("Couldn't open '{}'", path.display()))?;
+                    let reader = BufReader::new(file);
+                    state.locks.insert(
+                        name.to_string_lossy().to_string(),
+                        serde_yaml::from_reader(reader).map_err(|e| {
+                            CeplerError::StateCorrupt(format!("{}: {}", path.display(), e))
+                        })?,
                     );
                 }
             }
@@ -52,6 +80,7 @@ impl Database {
         Ok(Self {
             state,
             state_dir: dir,
+            workdir: workdir.to_path_buf(),
             ignore_queue,
         })
     }
@@ -84,6 +113,7 @@ impl Database {
         Ok(Self {
             state,
             state_dir: dir,
+            workdir: self.workdir.clone(),
             ignore_queue,
         })
     }
@@ -179,39 +209,215 @@ impl Database {
         }
     }
 
+    /// Looks up the state of `propagated_from` that was recorded with trigger `trigger`.
+    /// The current state and the propagation queue are searched first,
+    /// then the history of the state file.
+    pub fn find_propagated_state(
+        &self,
+        repo: &Repo,
+        propagated_from: &str,
+        trigger: &str,
+    ) -> Result<Option<DeployState>> {
+        if let Some(state) = self
+            .state
+            .environments
+            .get(propagated_from)
+            .and_then(|env| env.find_state(trigger))
+        {
+            return Ok(Some(state.clone()));
+        }
+        let env_file = format!("{}/{}.state", self.state_dir, propagated_from);
+        let env_path = Path::new(&env_file);
+        let mut ret = None;
+        repo.walk_commits_before(repo.gate_commit_hash()?, |commit| {
+            if let Some(env_state) = repo.get_file_content(commit, env_path, |bytes| {
+                EnvironmentState::from_reader(bytes)
+            })? {
+                if let Some(state) = env_state.find_state(trigger) {
+                    ret = Some(state.clone());
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })?;
+        Ok(ret)
+    }
+
+    /// Looks up a state that was deployed to `env` in the past.
+    /// `at` is either a trigger commit or an index into the deploy history
+    /// where `0` is the current state, `1` the one before and so on.
+    pub fn find_deployed_state(
+        &self,
+        repo: &Repo,
+        env: &str,
+        at: &str,
+    ) -> Result<Option<DeployState>> {
+        let index = parse_history_index(at);
+        let mut ret = None;
+        self.walk_history(repo, env, |n, state| {
+            let found = match index {
+                Some(index) => n == index,
+                None => state.head_commit.matches(at),
+            };
+            if found {
+                ret = Some(state.clone());
+            }
+            found
+        })?;
+        Ok(ret)
+    }
+
+    /// Returns up to `limit` states deployed to `env`, starting with the current one.
+    pub fn deploy_history(&self, repo: &Repo, env: &str, limit: usize) -> Result<Vec<DeployState>> {
+        let mut ret = Vec::new();
+        self.walk_history(repo, env, |_, state| {
+            if ret.len() < limit {
+                ret.push(state.clone());
+            }
+            ret.len() >= limit
+        })?;
+        Ok(ret)
+    }
+
+    /// Returns each distinct committed state of `env` together with the commit that recorded it.
+    /// States are told apart by their trigger and files, see [`DeployState::same_deploy`].
+    pub fn recorded_states(
+        &self,
+        repo: &Repo,
+        env: &str,
+    ) -> Result<Vec<(CommitHash, DeployState)>> {
+        let env_file = format!("{}/{}.state", self.state_dir, env);
+        let env_path = Path::new(&env_file);
+        let mut ret: Vec<(CommitHash, DeployState)> = Vec::new();
+        let mut visit = |commit: CommitHash| {
+            if let Some(env_state) = repo.get_file_content(commit.clone(), env_path, |bytes| {
+                EnvironmentState::from_reader(bytes)
+            })? {
+                match ret
+                    .iter_mut()
+                    .find(|(_, state)| state.same_deploy(&env_state.current))
+                {
+                    // The state file may have been rewritten since (eg. to prune the queue)
+                    Some(recorded) => recorded.0 = commit,
+                    None => ret.push((commit, env_state.current)),
+                }
+            }
+            Ok(true)
+        };
+        let head = repo.head_commit_summary()?.0;
+        visit(head.clone())?;
+        repo.walk_commits_before(head, visit)?;
+        Ok(ret)
+    }
+
+    /// Visits each distinct state deployed to `env` together with its history index
+    /// until `visit` returns true.
+    fn walk_history<F>(&self, repo: &Repo, env: &str, mut visit: F) -> Result<()>
+    where
+        F: FnMut(usize, &DeployState) -> bool,
+    {
+        let mut seen: Vec<DeployState> = Vec::new();
+        let mut visit = |state: &DeployState| {
+            if seen.iter().any(|seen| seen.same_deploy(state)) {
+                return false;
+            }
+            seen.push(state.clone());
+            visit(seen.len() - 1, state)
+        };
+        if let Some(state) = self.get_current_state(env) {
+            if visit(state) {
+                return Ok(());
+            }
+        }
+        let env_file = format!("{}/{}.state", self.state_dir, env);
+        let env_path = Path::new(&env_file);
+        repo.walk_commits_before(repo.head_commit_summary()?.0, |commit| {
+            if let Some(env_state) = repo.get_file_content(commit, env_path, |bytes| {
+                EnvironmentState::from_reader(bytes)
+            })? {
+                if visit(&env_state.current) {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })
+    }
+
     pub fn get_current_state(&self, env: &str) -> Option<&DeployState> {
         self.state.environments.get(env).map(|env| &env.current)
     }
 
+    /// States of `env` waiting to be propagated further.
+    pub fn get_propagation_queue(&self, env: &str) -> Vec<&DeployState> {
+        self.state
+            .environments
+            .get(env)
+            .map(|env| env.propagation_queue.iter().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get_lock(&self, env: &str) -> Option<&EnvironmentLock> {
+        self.state.locks.get(env)
+    }
+
+    pub fn set_lock(&mut self, name: String, lock: Option<EnvironmentLock>) -> Result<String> {
+        let ret = format!("{}/{}.lock", self.state_dir, &name);
+        if let Some(lock) = lock {
+            self.state.locks.insert(name, lock);
+        } else {
+            self.state.locks.remove(&name);
+        }
+        self.persist()?;
+        Ok(ret)
+    }
+
     fn persist(&self) -> Result<()> {
         use std::fs;
         use std::io::Write;
-        let _ = fs::remove_dir_all(&self.state_dir);
-        fs::create_dir_all(&self.state_dir)?;
+        let state_dir = self.workdir.join(&self.state_dir);
+        let _ = fs::remove_dir_all(&state_dir);
+        fs::create_dir_all(&state_dir)?;
         for (name, env) in self.state.environments.iter() {
-            let mut file = File::create(&format!("{}/{}.state", self.state_dir, name))?;
+            let mut file = File::create(state_dir.join(format!("{}.state", name)))?;
             let mut bytes = serde_yaml::to_vec(&env)?;
             bytes.extend("\n".as_bytes());
             file.write_all(&bytes)?;
         }
+        for (name, lock) in self.state.locks.iter() {
+            let mut file = File::create(state_dir.join(format!("{}.lock", name)))?;
+            let mut bytes = serde_yaml::to_vec(&lock)?;
+            bytes.extend("\n".as_bytes());
+            file.write_all(&bytes)?;
+        }
         Ok(())
     }
 }
 
+/// Short numbers are history indices, anything else is treated as a commit reference.
+fn parse_history_index(at: &str) -> Option<usize> {
+    if at.len() < 7 && at.chars().all(|c| c.is_ascii_digit()) {
+        at.parse().ok()
+    } else {
+        None
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 struct DbState {
     environments: BTreeMap<String, EnvironmentState>,
+    locks: BTreeMap<String, EnvironmentLock>,
 }
 
 impl DbState {
     fn prune_propagation_queue(&mut self, name: String) {
         let mut keep_states = 0;
-        let to_prune = self.environments.get(&name).unwrap();
+        let to_prune = if let Some(to_prune) = self.environments.get(&name) {
+            to_prune
+        } else {
+            return;
+        };
         for commit_hash in self.environments.iter().filter_map(|(env_name, state)| {
-            if env_name == &name
-                || state.propagated_from.is_none()
-                || state.propagated_from.as_ref().unwrap() != &name
-            {
+            if env_name == &name || state.propagated_from.as_ref() != Some(&name) {
                 None
             } else {
                 state.current.propagated_head.as_ref()
@@ -233,12 +439,13 @@ impl DbState {
                 keep_states = keep_states.max(idx + 1);
             }
         }
-        let to_prune = self.environments.get_mut(&name).unwrap();
-        to_prune.propagation_queue.drain(keep_states..);
+        if let Some(to_prune) = self.environments.get_mut(&name) {
+            to_prune.propagation_queue.drain(keep_states..);
+        }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct EnvironmentState {
     current: DeployState,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -250,12 +457,68 @@ pub struct EnvironmentState {
 
 impl EnvironmentState {
     fn from_reader(reader: impl Read) -> Result<Self> {
-        let state = serde_yaml::from_reader(reader)?;
+        let state = serde_yaml::from_reader(reader)
+            .map_err(|e| CeplerError::StateCorrupt(e.to_string()))?;
         Ok(state)
     }
+
+    /// Three-way merges the changes `upstream` and `local` made to `base`.
+    fn merge(base: Option<&Self>, upstream: &Self, local: &Self) -> Result<Self> {
+        let current = DeployState::merge(
+            base.map(|base| &base.current),
+            &upstream.current,
+            &local.current,
+        )?;
+        let propagated_from = merge_value(
+            "the upstream environment",
+            base.map(|base| &base.propagated_from),
+            &upstream.propagated_from,
+            &local.propagated_from,
+        )?;
+
+        // States are pushed to the front of the queue and pruned from the back
+        // so new states of both sides come first followed by the states
+        // of base neither side has pruned.
+        let contains = |queue: &VecDeque<DeployState>, state: &DeployState| {
+            queue.iter().any(|s| s.head_commit == state.head_commit)
+        };
+        let empty = VecDeque::new();
+        let base_queue = base.map(|base| &base.propagation_queue).unwrap_or(&empty);
+        let mut propagation_queue: VecDeque<DeployState> = VecDeque::new();
+        for state in local
+            .propagation_queue
+            .iter()
+            .chain(upstream.propagation_queue.iter())
+            .filter(|state| !contains(base_queue, state))
+            .chain(base_queue.iter().filter(|state| {
+                contains(&local.propagation_queue, state)
+                    && contains(&upstream.propagation_queue, state)
+            }))
+        {
+            if state.head_commit != current.head_commit && !contains(&propagation_queue, state) {
+                propagation_queue.push_back(state.clone());
+            }
+        }
+        Ok(Self {
+            current,
+            propagated_from,
+            propagation_queue,
+        })
+    }
+
+    fn find_state(&self, trigger: &str) -> Option<&DeployState> {
+        std::iter::once(&self.current)
+            .chain(self.propagation_queue.iter())
+            .find(|state| state.head_commit.matches(trigger))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvironmentLock {
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DeployState {
     pub head_commit: CommitHash,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -263,6 +526,9 @@ pub struct DeployState {
     #[serde(skip_serializing_if = "is_false")]
     #[serde(default)]
     any_dirty: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub only: Vec<String>,
     #[serde(default)]
     pub files: BTreeMap<FileIdent, FileState>,
 }
@@ -294,10 +560,85 @@ impl DeployState {
             head_commit,
             propagated_head: None,
             any_dirty: false,
+            only: Vec::new(),
             files: BTreeMap::new(),
         }
     }
 
+    /// Whether `other` deployed the same files from the same trigger.
+    /// Partial (`--only`) and full records of one trigger are different deploys.
+    fn same_deploy(&self, other: &Self) -> bool {
+        self.head_commit == other.head_commit && self.files == other.files
+    }
+
+    /// Merges the files of concurrently recorded states of the same trigger.
+    fn merge(base: Option<&Self>, upstream: &Self, local: &Self) -> Result<Self> {
+        if upstream == local {
+            return Ok(local.clone());
+        }
+        let head_commit = merge_value(
+            "the recorded trigger",
+            base.map(|base| &base.head_commit),
+            &upstream.head_commit,
+            &local.head_commit,
+        )?;
+        let propagated_head = merge_value(
+            "the propagated trigger",
+            base.map(|base| &base.propagated_head),
+            &upstream.propagated_head,
+            &local.propagated_head,
+        )?;
+        let mut files = BTreeMap::new();
+        for ident in upstream.files.keys().chain(local.files.keys()) {
+            let state = merge_value(
+                &format!("the state of '{}'", ident.name()),
+                base.map(|base| base.files.get(ident)).as_ref(),
+                &upstream.files.get(ident),
+                &local.files.get(ident),
+            )?;
+            if let Some(state) = state {
+                files.insert(ident.clone(), state.clone());
+            }
+        }
+        let only = if upstream.only.is_empty() || local.only.is_empty() {
+            Vec::new()
+        } else {
+            let mut only = upstream.only.clone();
+            only.extend(local.only.iter().cloned());
+            only.sort();
+            only.dedup();
+            only
+        };
+        Ok(Self {
+            head_commit,
+            propagated_head,
+            any_dirty: files.values().any(|f| f.dirty),
+            only,
+            files,
+        })
+    }
+
+    /// Restricts the state to the files matching `only`.
+    /// All other files are kept at the state they had in `last`.
+    pub fn restrict_to(&mut self, only: &[glob::Pattern], last: Option<&DeployState>) {
+        if only.is_empty() {
+            return;
+        }
+        let included = |ident: &FileIdent| {
+            only.iter()
+                .any(|p| p.matches_with(&ident.name(), MATCH_OPTIONS))
+        };
+        self.files.retain(|ident, _| included(ident));
+        if let Some(last) = last {
+            for (ident, state) in last.files.iter() {
+                if !included(ident) {
+                    self.files.insert(ident.clone(), state.clone());
+                }
+            }
+        }
+        self.only = only.iter().map(|p| p.as_str().to_string()).collect();
+    }
+
     pub fn diff(&self, other: &DeployState) -> Vec<FileDiff> {
         let mut removed_files: HashSet<&FileIdent> = other.files.keys().collect();
         let mut diffs: Vec<_> = self
@@ -311,6 +652,7 @@ impl DeployState {
                     } else if state.dirty
                         || last_state.dirty
                         || state.file_hash != last_state.file_hash
+                        || mode_changed(state.mode, last_state.mode)
                     {
                         Some(FileDiff {
                             ident: ident.clone(),
@@ -353,9 +695,16 @@ pub struct FileDiff {
     pub added: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FileState {
     pub file_hash: Option<FileHash>,
+    /// `None` for states recorded before modes were tracked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub mode: Option<FileMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub lfs_oid: Option<String>,
     #[serde(skip_serializing_if = "is_false")]
     #[serde(default)]
     pub dirty: bool,
@@ -374,6 +723,127 @@ impl fmt::Display for FileState {
     }
 }
 
+/// Three-way merges the content of an environment's `.state` file.
+/// Fails with `CeplerError::PushConflict` if both sides changed the same
+/// part of the state differently.
+pub(crate) fn merge_state_files(
+    base: Option<&[u8]>,
+    upstream: &[u8],
+    local: &[u8],
+) -> Result<Vec<u8>> {
+    let base = base.map(EnvironmentState::from_reader).transpose()?;
+    let merged = EnvironmentState::merge(
+        base.as_ref(),
+        &EnvironmentState::from_reader(upstream)?,
+        &EnvironmentState::from_reader(local)?,
+    )?;
+    let mut bytes = serde_yaml::to_vec(&merged)?;
+    bytes.extend("\n".as_bytes());
+    Ok(bytes)
+}
+
+fn merge_value<T: PartialEq + Clone>(
+    what: &str,
+    base: Option<&T>,
+    upstream: &T,
+    local: &T,
+) -> Result<T> {
+    if upstream == local || base == Some(upstream) {
+        Ok(local.clone())
+    } else if base == Some(local) {
+        Ok(upstream.clone())
+    } else {
+        Err(CeplerError::PushConflict(format!("Both sides changed {}", what)).into())
+    }
+}
+
 fn is_false(b: &bool) -> bool {
     !b
 }
+
+/// Whether `path` is an environment's `.state` file below a `.cepler` directory.
+pub(crate) fn is_state_file(path: &Path) -> bool {
+    path.extension() == Some(std::ffi::OsStr::new("state"))
+        && path
+            .components()
+            .any(|component| component.as_os_str() == STATE_DIR)
+}
+
+/// Modes are only compared if both states recorded one.
+pub(crate) fn mode_changed(mode: Option<FileMode>, other: Option<FileMode>) -> bool {
+    matches!((mode, other), (Some(mode), Some(other)) if mode != other)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(head_commit: &str, a: &str, b: &str, queue: &str) -> EnvironmentState {
+        let state = format!(
+            r#"current:
+  head_commit: {head_commit}
+  files:
+    "{{latest}}/a.yml":
+      file_hash: {a}
+      from_commit: {a}
+      message: msg
+    "{{latest}}/b.yml":
+      file_hash: {b}
+      from_commit: {b}
+      message: msg
+{queue}"#
+        );
+        EnvironmentState::from_reader(state.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn merge_concurrent_records() {
+        let queue = "propagation_queue:\n- head_commit: base\n";
+        let base = state("base", "a0", "b0", "");
+        let upstream = state("next", "a1", "b0", queue);
+        let local = state("next", "a0", "b1", queue);
+        let merged = EnvironmentState::merge(Some(&base), &upstream, &local).unwrap();
+        assert_eq!(merged, state("next", "a1", "b1", queue));
+
+        let local = state("other", "a0", "b1", queue);
+        let err = EnvironmentState::merge(Some(&base), &upstream, &local).unwrap_err();
+        assert_eq!(CeplerError::exit_code_of(&err), 21);
+    }
+
+    #[test]
+    fn diff_ignores_unrecorded_modes() {
+        let deploy_state = |mode: &str| {
+            serde_yaml::from_str::<DeployState>(&format!(
+                r#"head_commit: head
+files:
+  "{{latest}}/run.sh":
+    file_hash: hash
+    {mode}
+    from_commit: head
+    message: msg
+"#
+            ))
+            .unwrap()
+        };
+        let before_upgrade = deploy_state("");
+        let executable = deploy_state("mode: executable");
+        let regular = deploy_state("mode: regular");
+        assert!(executable.diff(&before_upgrade).is_empty());
+        assert!(before_upgrade.diff(&executable).is_empty());
+        assert_eq!(executable.diff(&regular).len(), 1);
+    }
+
+    #[test]
+    fn corrupt_state_file() {
+        let err = EnvironmentState::from_reader("current: [".as_bytes()).unwrap_err();
+        assert_eq!(CeplerError::exit_code_of(&err), 30);
+    }
+
+    #[test]
+    fn state_files() {
+        assert!(is_state_file(Path::new(".cepler/default/testflight.state")));
+        assert!(is_state_file(Path::new("team/.cepler/apps/staging.state")));
+        assert!(!is_state_file(Path::new("config/testflight.state")));
+        assert!(!is_state_file(Path::new(".cepler/default/testflight.yml")));
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+
+/// Failures that callers may want to handle specifically.
+/// Each variant maps to a stable process exit code (see [`CeplerError::exit_code`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CeplerError {
+    ConfigInvalid(String),
+    EnvironmentUnknown {
+        environment: String,
+        config: String,
+    },
+    UpstreamNotDeployed(String),
+    NotDeployed(String),
+    EnvironmentLocked {
+        environment: String,
+        reason: String,
+    },
+    GateInvalid(String),
+    TriggerOutOfSync {
+        environment: String,
+        wanted: String,
+        found: String,
+    },
+    GitAuth(String),
+    PushConflict(String),
+    StateCorrupt(String),
+}
+
+/// Exit code for errors that are not a [`CeplerError`].
+pub const EXIT_INTERNAL_ERROR: i32 = 1;
+/// Exit code of `check` when there is nothing to deploy.
+pub const EXIT_NOTHING_TO_DEPLOY: i32 = 2;
+
+impl CeplerError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CeplerError::ConfigInvalid(_) => 10,
+            CeplerError::EnvironmentUnknown { .. } => 11,
+            CeplerError::UpstreamNotDeployed(_) => 12,
+            CeplerError::NotDeployed(_) => 13,
+            CeplerError::EnvironmentLocked { .. } => 14,
+            CeplerError::GateInvalid(_) => 15,
+            CeplerError::TriggerOutOfSync { .. } => 16,
+            CeplerError::GitAuth(_) => 20,
+            CeplerError::PushConflict(_) => 21,
+            CeplerError::StateCorrupt(_) => 30,
+        }
+    }
+
+    /// Returns the exit code of the first `CeplerError` in the chain of `err`.
+    pub fn exit_code_of(err: &anyhow::Error) -> i32 {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<CeplerError>())
+            .map(CeplerError::exit_code)
+            .unwrap_or(EXIT_INTERNAL_ERROR)
+    }
+}
+
+impl fmt::Display for CeplerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CeplerError::ConfigInvalid(msg) => write!(f, "Invalid config: {}", msg),
+            CeplerError::EnvironmentUnknown {
+                environment,
+                config,
+            } => write!(
+                f,
+                "Environment '{}' not found in config '{}'",
+                environment, config
+            ),
+            CeplerError::UpstreamNotDeployed(env) => {
+                write!(f, "Previous environment '{}' not deployed yet", env)
+            }
+            CeplerError::NotDeployed(env) => write!(f, "Environment '{}' not deployed", env),
+            CeplerError::EnvironmentLocked {
+                environment,
+                reason,
+            } => write!(f, "Environment '{}' is locked: {}", environment, reason),
+            CeplerError::GateInvalid(msg) => write!(f, "Invalid gate: {}", msg),
+            CeplerError::TriggerOutOfSync {
+                environment,
+                wanted,
+                found,
+            } => write!(
+                f,
+                "Trigger of '{}' is out of sync: wanted '{}' but found '{}'",
+                environment, wanted, found
+            ),
+            CeplerError::GitAuth(msg) => write!(f, "Git authentication failed: {}", msg),
+            CeplerError::PushConflict(msg) => write!(f, "Couldn't push state: {}", msg),
+            CeplerError::StateCorrupt(msg) => write!(f, "Corrupt state file: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CeplerError {}
+
+/// Classifies errors returned by git operations against a remote.
+pub(crate) fn remote_error(err: git2::Error) -> anyhow::Error {
+    match err.code() {
+        git2::ErrorCode::Auth => CeplerError::GitAuth(err.message().to_string()).into(),
+        git2::ErrorCode::Certificate => {
+            CeplerError::GitAuth(format!("Host key verification failed: {}", err.message())).into()
+        }
+        git2::ErrorCode::NotFastForward => {
+            CeplerError::PushConflict(err.message().to_string()).into()
+        }
+        _ => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn exit_code_through_context() {
+        let err: anyhow::Result<()> = Err(CeplerError::StateCorrupt("bad".to_string()).into());
+        let err = err.context("Couldn't open database").unwrap_err();
+        assert_eq!(CeplerError::exit_code_of(&err), 30);
+        assert_eq!(
+            CeplerError::exit_code_of(&anyhow::anyhow!("boom")),
+            EXIT_INTERNAL_ERROR
+        );
+    }
+}
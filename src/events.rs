@@ -0,0 +1,72 @@
+use std::fmt;
+
+/// Progress reported while checking, preparing or recording an environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    FileAdded(String),
+    FileChanged(String),
+    FileRemoved(String),
+    EnvironmentLocked {
+        environment: String,
+        reason: String,
+    },
+    /// `reproduce --output` writes submodules as empty directories.
+    SubmoduleNotCheckedOut {
+        path: String,
+        commit: String,
+    },
+    RecordingState,
+    CommittingState,
+    CommittingLock,
+    RemovingLock,
+    ResettingHead,
+    Pushing,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::FileAdded(name) => write!(f, "File {} was added", name),
+            Event::FileChanged(name) => write!(f, "File {} changed", name),
+            Event::FileRemoved(name) => write!(f, "File {} was removed", name),
+            Event::EnvironmentLocked {
+                environment,
+                reason,
+            } => write!(f, "Environment '{}' is locked: {}", environment, reason),
+            Event::SubmoduleNotCheckedOut { path, commit } => write!(
+                f,
+                "Submodule {} is left empty, check out commit {} yourself",
+                path, commit
+            ),
+            Event::RecordingState => write!(f, "Recording current state"),
+            Event::CommittingState => write!(f, "Adding commit to repository to persist state"),
+            Event::CommittingLock => write!(f, "Adding commit to repository to persist lock"),
+            Event::RemovingLock => write!(f, "Adding commit to repository to remove lock"),
+            Event::ResettingHead => write!(f, "Reseting head to have a clean workspace"),
+            Event::Pushing => write!(f, "Pushing to remote"),
+        }
+    }
+}
+
+/// Receives the events emitted by cepler.
+pub trait EventSink: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+impl<F> EventSink for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Prints every event to stderr. This is what the cli uses.
+pub struct StderrSink;
+
+impl EventSink for StderrSink {
+    fn event(&self, event: &Event) {
+        eprintln!("{}", event)
+    }
+}
@@ -0,0 +1,379 @@
+//! Shallow and partial clones aren't supported by libgit2 so creating and
+//! fetching into them is delegated to the `git` cli.
+use super::{error::CeplerError, repo::GitCredentials};
+use anyhow::*;
+use git2::Oid;
+use std::{
+    collections::hash_map::RandomState,
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Answers the prompts of git and ssh with the secrets passed via the environment.
+const ASKPASS: &str = r#"#!/bin/sh
+case "$1" in
+  Username*) printf '%s\n' "${CEPLER_GIT_USERNAME:-git}" ;;
+  Password*) printf '%s\n' "$CEPLER_GIT_PASSWORD" ;;
+  *) printf '%s\n' "$CEPLER_GIT_PASSPHRASE" ;;
+esac
+"#;
+
+/// Clones `branch` of `url` into `dir` fetching at most `depth` commits and,
+/// if `blobless` is set, only the file contents needed for the checkout.
+pub fn clone(
+    url: &str,
+    branch: &str,
+    dir: &Path,
+    depth: Option<u32>,
+    blobless: bool,
+    credentials: &GitCredentials,
+    known_hosts: Option<&str>,
+) -> Result<()> {
+    fs::create_dir_all(dir).context(format!("Couldn't create '{}'", dir.display()))?;
+    git(dir, &["init", "--quiet"])?;
+    git(dir, &["remote", "add", "-t", branch, "origin", url])?;
+    let depth = depth.map(|depth| format!("--depth={}", depth));
+    let mut args = vec!["fetch", "--quiet", "--no-tags"];
+    args.extend(depth.as_deref());
+    if blobless {
+        args.push("--filter=blob:none");
+    }
+    args.push("origin");
+    let auth = Auth::new(credentials, known_hosts)?;
+    git_remote(dir, &args, &auth).context(format!("Couldn't fetch '{}'", url))?;
+    git(
+        dir,
+        &[
+            "checkout",
+            "--quiet",
+            "-B",
+            branch,
+            &format!("origin/{}", branch),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Fetches `branches` from `origin` into `refs/remotes/origin`.
+pub fn fetch(
+    dir: &Path,
+    branches: &[String],
+    credentials: &GitCredentials,
+    known_hosts: Option<&str>,
+) -> Result<()> {
+    let refspecs: Vec<_> = branches
+        .iter()
+        .map(|branch| format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch))
+        .collect();
+    let mut args = vec!["fetch", "--quiet", "--no-tags", "origin"];
+    args.extend(refspecs.iter().map(|refspec| refspec.as_str()));
+    let auth = Auth::new(credentials, known_hosts)?;
+    git_remote(dir, &args, &auth).context("Couldn't fetch origin")?;
+    Ok(())
+}
+
+/// Fetches `by` more commits of history in a shallow clone.
+pub fn deepen(
+    dir: &Path,
+    by: u32,
+    credentials: &GitCredentials,
+    known_hosts: Option<&str>,
+) -> Result<()> {
+    let auth = Auth::new(credentials, known_hosts)?;
+    git_remote(
+        dir,
+        &["fetch", "--quiet", "--no-tags", &format!("--deepen={}", by)],
+        &auth,
+    )
+    .context("Couldn't deepen shallow clone")?;
+    Ok(())
+}
+
+/// Fetches the complete history into a shallow clone.
+pub fn unshallow(
+    dir: &Path,
+    credentials: &GitCredentials,
+    known_hosts: Option<&str>,
+) -> Result<()> {
+    let auth = Auth::new(credentials, known_hosts)?;
+    git_remote(
+        dir,
+        &["fetch", "--quiet", "--no-tags", "--unshallow"],
+        &auth,
+    )
+    .context("Couldn't unshallow clone")?;
+    Ok(())
+}
+
+/// Fetches objects missing from a partial clone the same way git does it lazily.
+pub fn fetch_objects(
+    dir: &Path,
+    oids: &[Oid],
+    credentials: &GitCredentials,
+    known_hosts: Option<&str>,
+) -> Result<()> {
+    if oids.is_empty() {
+        return Ok(());
+    }
+    let auth = Auth::new(credentials, known_hosts)?;
+    let mut child = auth
+        .command(dir)
+        .args([
+            "-c",
+            "fetch.negotiationAlgorithm=noop",
+            "fetch",
+            "--quiet",
+            "--no-tags",
+            "--no-write-fetch-head",
+            "--recurse-submodules=no",
+            "--filter=blob:none",
+            "--stdin",
+            "origin",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Couldn't run 'git'")?;
+    let stdin = child.stdin.as_mut().context("Couldn't open stdin of git")?;
+    for oid in oids {
+        writeln!(stdin, "{}", oid)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Couldn't fetch missing objects: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Pushes `refspec` to `origin`.
+/// Fails with `CeplerError::PushConflict` if the remote rejects the update.
+pub fn push(
+    dir: &Path,
+    refspec: &str,
+    credentials: &GitCredentials,
+    known_hosts: Option<&str>,
+) -> Result<()> {
+    let auth = Auth::new(credentials, known_hosts)?;
+    let output = auth
+        .command(dir)
+        .args(["push", "--quiet", "--porcelain", "origin", refspec])
+        .stdin(Stdio::null())
+        .output()
+        .context("Couldn't run 'git'")?;
+    if output.status.success() {
+        return Ok(());
+    }
+    // Rejected refs are flagged with '!' in the porcelain output
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if let Some(rejection) = stdout.lines().find(|line| line.starts_with('!')) {
+        return Err(CeplerError::PushConflict(format!(
+            "'{}' was rejected: {}",
+            refspec,
+            rejection.trim_start_matches('!').trim()
+        ))
+        .into());
+    }
+    Err(anyhow!(
+        "Couldn't push to remote: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    ))
+}
+
+/// Credentials and host key policy of a single git invocation.
+/// Secrets are passed via the environment and the files ssh needs are written
+/// to a private temporary directory that is removed once git finished.
+struct Auth {
+    dir: Option<PathBuf>,
+    envs: Vec<(&'static str, String)>,
+    config: Vec<String>,
+}
+
+impl Auth {
+    fn new(credentials: &GitCredentials, known_hosts: Option<&str>) -> Result<Self> {
+        let mut auth = Self {
+            dir: None,
+            envs: Vec::new(),
+            config: Vec::new(),
+        };
+        let mut ssh = vec!["ssh".to_string()];
+        match known_hosts {
+            Some(known_hosts) => {
+                let file = auth.write("known_hosts", known_hosts, 0o600)?;
+                ssh.push(format!(
+                    "-o UserKnownHostsFile={} -o StrictHostKeyChecking=yes",
+                    quote(&file)
+                ));
+            }
+            // Keys in the user's known_hosts are still verified, unknown hosts are trusted on first use
+            None => ssh.push("-o StrictHostKeyChecking=accept-new".into()),
+        }
+        let key = match (&credentials.private_key, &credentials.private_key_file) {
+            (Some(key), _) => Some(auth.write("private_key", key, 0o600)?),
+            (None, Some(file)) => Some(
+                fs::canonicalize(file).context(format!("Couldn't find private key '{}'", file))?,
+            ),
+            (None, None) => None,
+        };
+        if let Some(key) = key {
+            ssh.push(format!("-i {} -o IdentitiesOnly=yes", quote(&key)));
+        }
+        auth.envs.push(("GIT_SSH_COMMAND", ssh.join(" ")));
+        if let Some(username) = credentials.username.as_ref() {
+            auth.config
+                .push(format!("credential.username={}", username));
+            auth.envs.push(("CEPLER_GIT_USERNAME", username.clone()));
+        }
+        if credentials.passphrase.is_some() || credentials.password.is_some() {
+            let askpass = auth.write("askpass", ASKPASS, 0o700)?;
+            let askpass = askpass.to_string_lossy().to_string();
+            if let Some(passphrase) = credentials.passphrase.as_ref() {
+                auth.envs.push(("SSH_ASKPASS", askpass.clone()));
+                auth.envs.push(("SSH_ASKPASS_REQUIRE", "force".into()));
+                auth.envs
+                    .push(("CEPLER_GIT_PASSPHRASE", passphrase.clone()));
+            }
+            if let Some(password) = credentials.password.as_ref() {
+                // The empty helper resets helpers configured globally so git asks GIT_ASKPASS
+                auth.config.push("credential.helper=".into());
+                auth.envs.push(("GIT_ASKPASS", askpass));
+                auth.envs.push(("CEPLER_GIT_PASSWORD", password.clone()));
+            }
+        }
+        Ok(auth)
+    }
+
+    /// A `git` command in `dir` that accesses remotes with these credentials.
+    fn command(&self, dir: &Path) -> Command {
+        let mut command = Command::new("git");
+        command.arg("-C").arg(dir);
+        for config in self.config.iter() {
+            command.arg("-c").arg(config);
+        }
+        command
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .envs(self.envs.iter().map(|(name, value)| (name, value)));
+        command
+    }
+
+    fn write(&mut self, name: &str, content: &str, mode: u32) -> Result<PathBuf> {
+        let dir = match self.dir.as_ref() {
+            Some(dir) => dir.clone(),
+            None => {
+                let dir = create_private_dir()?;
+                self.dir = Some(dir.clone());
+                dir
+            }
+        };
+        let path = dir.join(name);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode);
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        let mut file = options
+            .open(&path)
+            .context(format!("Couldn't write '{}'", path.display()))?;
+        file.write_all(content.as_bytes())?;
+        if !content.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        Ok(path)
+    }
+}
+
+impl Drop for Auth {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.as_ref() {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Creates a directory below the system's temp dir that only the current user can access.
+fn create_private_dir() -> Result<PathBuf> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    loop {
+        let suffix = RandomState::new().build_hasher().finish();
+        let dir = std::env::temp_dir().join(format!("cepler-git-{:x}", suffix));
+        match builder.create(&dir) {
+            Result::Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).context(format!("Couldn't create '{}'", dir.display())),
+        }
+    }
+}
+
+/// Quotes `path` for the shell git runs `GIT_SSH_COMMAND` with.
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+/// Runs a git command that doesn't access any remote.
+fn git(dir: &Path, args: &[&str]) -> Result<()> {
+    run(
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .env("GIT_TERMINAL_PROMPT", "0"),
+        args,
+    )
+}
+
+fn git_remote(dir: &Path, args: &[&str], auth: &Auth) -> Result<()> {
+    run(&mut auth.command(dir), args)
+}
+
+fn run(command: &mut Command, args: &[&str]) -> Result<()> {
+    let output = command
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .context("Couldn't run 'git'")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "'git {}' failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ssh_command(known_hosts: Option<&str>) -> String {
+        let auth = Auth::new(&GitCredentials::default(), known_hosts).unwrap();
+        auth.envs
+            .iter()
+            .find(|(name, _)| *name == "GIT_SSH_COMMAND")
+            .map(|(_, command)| command.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn verifies_host_keys() {
+        let command = ssh_command(None);
+        assert!(command.contains("StrictHostKeyChecking=accept-new"));
+        assert!(!command.contains("UserKnownHostsFile"));
+        assert!(ssh_command(Some("github.com ssh-ed25519 AAAA\n"))
+            .contains("StrictHostKeyChecking=yes"));
+    }
+}
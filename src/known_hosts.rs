@@ -0,0 +1,135 @@
+use anyhow::*;
+use sha2::{Digest, Sha256};
+
+/// Port of ssh remotes that don't specify one.
+const DEFAULT_SSH_PORT: u16 = 22;
+
+/// Host keys parsed from the content of an OpenSSH `known_hosts` file.
+/// Hashed host names and markers like `@cert-authority` are not supported.
+pub struct KnownHosts {
+    entries: Vec<KnownHost>,
+}
+
+struct KnownHost {
+    /// Host names along with the port they apply to.
+    hosts: Vec<(String, u16)>,
+    /// sha256 of the raw host key, which is all libgit2 exposes of the server's key.
+    key_sha256: Vec<u8>,
+}
+
+impl KnownHosts {
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+                continue;
+            }
+            if line.starts_with("|1|") {
+                return Err(anyhow!(
+                    "Hashed host name on line {} of known_hosts is not supported - list the host name in plain text instead",
+                    n + 1
+                ));
+            }
+            let mut fields = line.split_whitespace();
+            let (hosts, key) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(hosts), Some(_key_type), Some(key)) => (hosts, key),
+                _ => return Err(anyhow!("Invalid known_hosts entry on line {}", n + 1)),
+            };
+            let key = base64::decode(key)
+                .context(format!("Invalid host key on line {} of known_hosts", n + 1))?;
+            entries.push(KnownHost {
+                hosts: hosts
+                    .split(',')
+                    .map(|host| {
+                        host_and_port(host).context(format!(
+                            "Invalid host '{}' on line {} of known_hosts",
+                            host,
+                            n + 1
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+                key_sha256: Sha256::digest(&key).to_vec(),
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    /// Returns true if a key of `host` listening on `port` hashes to `key_sha256`.
+    pub fn verify(&self, host: &str, port: u16, key_sha256: &[u8]) -> bool {
+        self.entries.iter().any(|entry| {
+            entry
+                .hosts
+                .iter()
+                .any(|(known, known_port)| known == host && *known_port == port)
+                && entry.key_sha256 == key_sha256
+        })
+    }
+}
+
+/// Returns the port of an ssh remote url like `ssh://git@host:2222/repo.git`.
+/// Scp-like urls (`git@host:repo.git`) always use the default port.
+pub fn ssh_port(url: &str) -> u16 {
+    url.split_once("://")
+        .and_then(|(_, rest)| rest.split('/').next())
+        .map(|authority| authority.rsplit('@').next().unwrap_or(authority))
+        .and_then(|host| host.rsplit_once(':'))
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(DEFAULT_SSH_PORT)
+}
+
+/// Splits entries like `[host]:2222` into host and port.
+fn host_and_port(host: &str) -> Result<(String, u16)> {
+    match host.strip_prefix('[').and_then(|host| host.split_once(']')) {
+        Some((host, port)) => {
+            let port = match port.strip_prefix(':') {
+                Some(port) => port.parse().context("Invalid port")?,
+                None => DEFAULT_SSH_PORT,
+            };
+            Ok((host.to_string(), port))
+        }
+        None => Ok((host.to_string(), DEFAULT_SSH_PORT)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_host_key() {
+        let known_hosts = KnownHosts::parse(
+            "# comment
+github.com,[git.example.com]:2222 ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl
+",
+        )
+        .unwrap();
+        let key =
+            base64::decode("AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl")
+                .unwrap();
+        let hash = Sha256::digest(&key);
+        assert!(known_hosts.verify("github.com", 22, &hash));
+        assert!(known_hosts.verify("git.example.com", 2222, &hash));
+        assert!(!known_hosts.verify("git.example.com", 22, &hash));
+        assert!(!known_hosts.verify("github.com", 2222, &hash));
+        assert!(!known_hosts.verify("gitlab.com", 22, &hash));
+        assert!(!known_hosts.verify("github.com", 22, &[0; 32]));
+    }
+
+    #[test]
+    fn reject_hashed_host_names() {
+        let err = KnownHosts::parse(
+            "|1|JfKTdBh7rNbXkVAQCRp4OQoPfmI=|USECr3SWf1JUPsms5AqfD5QfxkM= ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl",
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("Hashed host name on line 1"));
+    }
+
+    #[test]
+    fn port_of_url() {
+        assert_eq!(ssh_port("ssh://git@git.example.com:2222/repo.git"), 2222);
+        assert_eq!(ssh_port("ssh://git@git.example.com/repo.git"), 22);
+        assert_eq!(ssh_port("git@github.com:org/repo.git"), 22);
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::*;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// Pointer files are never larger than this.
+pub const MAX_POINTER_SIZE: usize = 1024;
+
+const VERSION_LINE: &str = "version https://git-lfs.github.com/spec/v1";
+
+/// The content of a git LFS pointer file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LfsPointer {
+    /// Hex encoded sha256 of the object.
+    pub oid: String,
+    pub size: u64,
+}
+
+impl LfsPointer {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > MAX_POINTER_SIZE {
+            return None;
+        }
+        let content = std::str::from_utf8(bytes).ok()?;
+        let mut lines = content.lines();
+        if lines.next()? != VERSION_LINE {
+            return None;
+        }
+        let mut oid = None;
+        let mut size = None;
+        for line in lines {
+            if let Some(hash) = line.strip_prefix("oid sha256:") {
+                if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    oid = Some(hash.to_string());
+                }
+            } else if let Some(bytes) = line.strip_prefix("size ") {
+                size = bytes.parse().ok();
+            }
+        }
+        Some(Self {
+            oid: oid?,
+            size: size?,
+        })
+    }
+
+    /// Location of the object in the LFS store below the common git directory `common_dir`,
+    /// which is shared by all worktrees of a repository.
+    pub fn object_path(&self, common_dir: &Path) -> PathBuf {
+        common_dir
+            .join("lfs")
+            .join("objects")
+            .join(&self.oid[..2])
+            .join(&self.oid[2..4])
+            .join(&self.oid)
+    }
+}
+
+/// Returns the LFS object id of the file at `path`.
+/// If the file is still a pointer the id is taken from the pointer.
+pub fn file_oid(path: &Path) -> Result<String> {
+    let mut file = File::open(path).context(format!("Couldn't open '{}'", path.display()))?;
+    let mut head = Vec::new();
+    (&mut file)
+        .take(MAX_POINTER_SIZE as u64 + 1)
+        .read_to_end(&mut head)?;
+    if let Some(pointer) = LfsPointer::parse(&head) {
+        return Ok(pointer.oid);
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&head);
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Replaces the pointer file at `path` with the object from the LFS store of `common_dir`.
+/// Files that are not pointers are left untouched.
+pub fn materialize(common_dir: &Path, path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Result::Ok(meta) if meta.is_file() && meta.len() <= MAX_POINTER_SIZE as u64 => (),
+        _ => return Ok(()),
+    }
+    let bytes = std::fs::read(path).context(format!("Couldn't read '{}'", path.display()))?;
+    if LfsPointer::parse(&bytes).is_some() {
+        let content = smudge(common_dir, bytes)?;
+        std::fs::write(path, content).context(format!("Couldn't write '{}'", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Returns the content of the object `bytes` points to or `bytes` if it isn't a pointer.
+pub fn smudge(common_dir: &Path, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if let Some(pointer) = LfsPointer::parse(&bytes) {
+        let object = pointer.object_path(common_dir);
+        std::fs::read(&object).context(format!(
+            "LFS object '{}' is missing from the local store - try running 'git lfs fetch'",
+            pointer.oid
+        ))
+    } else {
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_pointer() {
+        let pointer = "version https://git-lfs.github.com/spec/v1
+oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393
+size 12345
+";
+        assert_eq!(
+            LfsPointer::parse(pointer.as_bytes()),
+            Some(LfsPointer {
+                oid: "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393".to_string(),
+                size: 12345
+            })
+        );
+        assert_eq!(LfsPointer::parse(b"field: value\n"), None);
+    }
+}
@@ -1,10 +1,21 @@
 #![cfg_attr(feature = "fail-on-warnings", deny(warnings))]
 #![cfg_attr(feature = "fail-on-warnings", deny(clippy::all))]
 
+mod api;
 mod concourse;
 mod config;
 mod database;
+mod error;
+mod events;
+mod git_cli;
+mod known_hosts;
+mod lfs;
+mod metrics;
 mod repo;
+mod report;
+mod serve;
+mod signing;
 mod workspace;
 
 pub mod cli;
+pub use api::*;
@@ -1,5 +1,8 @@
-use cepler::cli;
+use cepler::{cli, CeplerError};
 
-fn main() -> Result<(), anyhow::Error> {
-    cli::run()
+fn main() {
+    if let Err(err) = cli::run() {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(CeplerError::exit_code_of(&err));
+    }
 }
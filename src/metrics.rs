@@ -0,0 +1,131 @@
+use super::{config::*, error::*, events::Event, repo::*, workspace::Workspace};
+use anyhow::*;
+use std::{fmt::Write, path::Path, sync::Arc};
+
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<(String, i64)>,
+}
+
+impl Metric {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            samples: Vec::new(),
+        }
+    }
+}
+
+/// Renders metrics about all environments of `configs` in the Prometheus text format.
+/// `credentials` and `known_hosts` are used to fetch history missing from shallow clones.
+pub fn render(
+    workdir: &Path,
+    configs: &[(Config, String)],
+    gates: Option<&GatesConfig>,
+    ignore_queue: bool,
+    credentials: &GitCredentials,
+    known_hosts: Option<String>,
+) -> Result<String> {
+    let mut last_record = Metric::new(
+        "cepler_last_record_timestamp_seconds",
+        "Commit time of the last recorded state of the environment.",
+    );
+    let mut pending_files = Metric::new(
+        "cepler_pending_files",
+        "Number of files that would change on the next deploy.",
+    );
+    let mut locked = Metric::new(
+        "cepler_locked",
+        "1 if the environment is locked, 0 otherwise.",
+    );
+    let mut upstream_queue = Metric::new(
+        "cepler_upstream_queue_length",
+        "Number of states of the upstream environment waiting to be propagated.",
+    );
+    let mut commits_behind = Metric::new(
+        "cepler_commits_behind_upstream",
+        "Number of commits between the trigger of the environment and the trigger of its upstream.",
+    );
+
+    let mut repo = Repo::open(workdir, None)?;
+    repo.set_credentials(credentials.clone(), known_hosts);
+    for (config, config_path) in configs {
+        repo.set_first_parent(config.first_parent);
+        let mut ws = Workspace::new(workdir, &config.scope, config_path.clone(), ignore_queue)?;
+        ws.set_event_sink(Arc::new(|_: &Event| ()));
+        let mut envs: Vec<_> = config.environments.values().collect();
+        envs.sort_by(|a, b| a.name.cmp(&b.name));
+        for env in envs {
+            let labels = format!(
+                "deployment=\"{}\",environment=\"{}\"",
+                escape(&config.scope),
+                escape(&env.name)
+            );
+            if let Some(time) = ws.last_recorded_at(&repo, env)? {
+                last_record.samples.push((labels.clone(), time));
+            }
+
+            let gate = match gates {
+                Some(gates) => gates.get_gate(&env.name)?,
+                None => None,
+            };
+            repo.set_gate(gate)?;
+            locked
+                .samples
+                .push((labels.clone(), ws.get_lock(env).is_some() as i64));
+            // Files keep piling up while the environment is locked
+            match ws.pending_diff_in(&repo, env) {
+                Result::Ok(report) => pending_files.samples.push((
+                    labels.clone(),
+                    report.map(|report| report.diffs.len()).unwrap_or(0) as i64,
+                )),
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<CeplerError>(),
+                        Some(CeplerError::UpstreamNotDeployed(_))
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+
+            if let Some(upstream) = env.propagated_from() {
+                let upstream = config.environment(upstream)?;
+                upstream_queue.samples.push((
+                    labels.clone(),
+                    ws.get_propagation_queue(upstream).len() as i64,
+                ));
+                if let (Some(state), Some(upstream_state)) =
+                    (ws.get_current_state(env), ws.get_current_state(upstream))
+                {
+                    let behind =
+                        repo.commits_between(&state.head_commit, &upstream_state.head_commit)?;
+                    commits_behind.samples.push((labels, behind as i64));
+                }
+            }
+        }
+    }
+
+    let mut ret = String::new();
+    for metric in [
+        last_record,
+        pending_files,
+        locked,
+        upstream_queue,
+        commits_behind,
+    ] {
+        writeln!(ret, "# HELP {} {}", metric.name, metric.help)?;
+        writeln!(ret, "# TYPE {} gauge", metric.name)?;
+        for (labels, value) in metric.samples {
+            writeln!(ret, "{}{{{}}} {}", metric.name, labels, value)?;
+        }
+    }
+    Ok(ret)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
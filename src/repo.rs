@@ -1,21 +1,36 @@
-use super::config::{default_scope, MATCH_OPTIONS};
+use super::{
+    config::{default_scope, CommitConfig, MATCH_OPTIONS},
+    database,
+    error::*,
+    git_cli,
+    known_hosts::{self, KnownHosts},
+    lfs::{self, LfsPointer},
+    signing,
+};
 use anyhow::*;
 use git2::{
-    build::CheckoutBuilder, BranchType, Commit, Cred, MergeOptions, Object, ObjectType, Oid,
-    PushOptions, RebaseOptions, RemoteCallbacks, Repository, ResetType, Signature, TreeWalkMode,
-    TreeWalkResult,
+    build::CheckoutBuilder, BranchType, Commit, Cred, CredentialType, ErrorClass, ErrorCode, Index,
+    MergeOptions, Object, ObjectType, Oid, Patch, PushOptions, RebaseOptions, RemoteCallbacks,
+    Repository, ResetType, Signature, Tree, TreeWalkMode, TreeWalkResult,
 };
 use glob::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashSet, VecDeque},
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
     fmt,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct FileHash(String);
+impl FileHash {
+    pub fn inner(self) -> String {
+        self.0
+    }
+}
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct CommitHash(String);
@@ -32,53 +47,193 @@ impl CommitHash {
     pub fn to_short_ref(&self) -> String {
         self.0.chars().take(7).collect()
     }
+
+    /// Returns true if `reference` is this hash or an abbreviation of it.
+    pub fn matches(&self, reference: &str) -> bool {
+        !reference.is_empty() && self.0.starts_with(reference)
+    }
 }
 
-pub fn hash_file<P: AsRef<Path>>(file: P) -> Option<FileHash> {
+/// The mode git records for a file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileMode {
+    #[default]
+    Regular,
+    Executable,
+    Symlink,
+    Submodule,
+}
+impl FileMode {
+    fn from_git(mode: i32) -> Self {
+        if mode == i32::from(git2::FileMode::BlobExecutable) {
+            FileMode::Executable
+        } else if mode == i32::from(git2::FileMode::Link) {
+            FileMode::Symlink
+        } else if mode == i32::from(git2::FileMode::Commit) {
+            FileMode::Submodule
+        } else {
+            FileMode::Regular
+        }
+    }
+}
+
+/// Hashes `file` the way git would store it. Symlinks are hashed by their target.
+pub fn hash_file<P: AsRef<Path>>(file: P) -> Result<Option<FileHash>> {
     let path = file.as_ref();
-    if path.is_file() {
-        Some(FileHash(
-            Oid::hash_file(ObjectType::Blob, path)
-                .expect("Couldn't hash object")
-                .to_string(),
-        ))
+    let oid = match file_mode(path)? {
+        None => return Ok(None),
+        Some(FileMode::Symlink) => {
+            let target = std::fs::read_link(path)
+                .context(format!("Couldn't read link '{}'", path.display()))?;
+            Oid::hash_object(ObjectType::Blob, target.to_string_lossy().as_bytes())
+        }
+        Some(_) => Oid::hash_file(ObjectType::Blob, path),
+    }
+    .context(format!("Couldn't hash file '{}'", path.display()))?;
+    Ok(Some(FileHash(oid.to_string())))
+}
+
+/// Hashes the submodule at `path` by the commit it has checked out.
+/// Returns `None` if the submodule has not been initialized.
+pub fn hash_submodule<P: AsRef<Path>>(path: P) -> Result<Option<FileHash>> {
+    let path = path.as_ref();
+    if !path.join(".git").exists() {
+        return Ok(None);
+    }
+    let oid = Repository::open(path)
+        .and_then(|repo| repo.head()?.peel_to_commit().map(|commit| commit.id()))
+        .context(format!(
+            "Couldn't read the checked out commit of submodule '{}'",
+            path.display()
+        ))?;
+    Ok(Some(FileHash(oid.to_string())))
+}
+
+/// Returns the mode of `file` or `None` if it is neither a file nor a symlink.
+/// Whether a directory is a submodule is only known from the tree that records it.
+pub fn file_mode<P: AsRef<Path>>(file: P) -> Result<Option<FileMode>> {
+    let meta = match std::fs::symlink_metadata(file.as_ref()) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("Couldn't stat '{}'", file.as_ref().display())),
+    };
+    if meta.file_type().is_symlink() {
+        Ok(Some(FileMode::Symlink))
+    } else if meta.is_file() && is_executable(&meta) {
+        Ok(Some(FileMode::Executable))
+    } else if meta.is_file() {
+        Ok(Some(FileMode::Regular))
     } else {
-        None
+        Ok(None)
     }
 }
 
+#[cfg(unix)]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o100 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &std::fs::Metadata) -> bool {
+    false
+}
+
 pub struct GitConfig {
     pub url: String,
     pub branch: String,
     pub gates_branch: Option<String>,
-    pub private_key: String,
+    pub credentials: GitCredentials,
+    /// Content of a known_hosts file to verify the host key of ssh remotes against.
+    pub known_hosts: Option<String>,
+    /// Number of commits to clone. History is deepened when cepler needs more of it.
+    pub depth: Option<u32>,
+    /// Clone without file contents, which are fetched when they are needed.
+    pub blobless: bool,
     pub dir: String,
 }
 
+/// Credentials for accessing a remote. Which of them are used depends on what the remote asks for.
+/// Without a key ssh remotes are accessed via ssh-agent, without a password https remotes
+/// via the configured git credential helper.
+#[derive(Clone, Default)]
+pub struct GitCredentials {
+    pub username: Option<String>,
+    /// Password or token for https remotes.
+    pub password: Option<String>,
+    /// Content of a private key for ssh remotes.
+    pub private_key: Option<String>,
+    /// Path to a private key for ssh remotes.
+    pub private_key_file: Option<String>,
+    /// Passphrase of the private key.
+    pub passphrase: Option<String>,
+}
+
 pub struct Repo {
     inner: Repository,
     gate: Option<Oid>,
+    last_changed: RefCell<HashMap<(Oid, PathBuf), (CommitHash, String)>>,
+    commit_config: CommitConfig,
+    first_parent: bool,
+    credentials: GitCredentials,
+    known_hosts: Option<String>,
 }
 
+const INDEX_ENTRY_STAGE_MASK: u16 = 0x3000;
+
+/// Number of commits fetched whenever a walk reaches the boundary of a shallow clone.
+const DEEPEN_BY: u32 = 100;
+/// Times a shallow clone is deepened looking for a commit before the full history is fetched.
+const MAX_DEEPEN_ROUNDS: usize = 3;
+
+const DEFAULT_AUTHOR_NAME: &str = "Cepler";
+const DEFAULT_AUTHOR_EMAIL: &str = "bot@cepler.io";
+
 impl Repo {
     pub fn clone(
         GitConfig {
             url,
             branch,
-            private_key,
+            credentials,
+            known_hosts,
+            depth,
+            blobless,
             dir,
             ..
         }: GitConfig,
     ) -> Result<Self> {
-        let callbacks = remote_callbacks(private_key);
-        let mut fo = git2::FetchOptions::new();
-        fo.remote_callbacks(callbacks);
+        let inner = if depth.is_some() || blobless {
+            git_cli::clone(
+                &url,
+                &branch,
+                Path::new(&dir),
+                depth,
+                blobless,
+                &credentials,
+                known_hosts.as_deref(),
+            )?;
+            Repository::open(&dir)?
+        } else {
+            let callbacks =
+                remote_callbacks(credentials.clone(), host_keys(&url, known_hosts.clone())?);
+            let mut fo = git2::FetchOptions::new();
+            fo.remote_callbacks(callbacks);
 
-        let mut builder = git2::build::RepoBuilder::new();
-        builder.fetch_options(fo);
-        builder.branch(&branch);
-        let inner = builder.clone(&url, Path::new(&dir))?;
-        Ok(Self { inner, gate: None })
+            let mut builder = git2::build::RepoBuilder::new();
+            builder.fetch_options(fo);
+            builder.branch(&branch);
+            builder.clone(&url, Path::new(&dir)).map_err(remote_error)?
+        };
+        Ok(Self {
+            inner,
+            gate: None,
+            last_changed: RefCell::new(HashMap::new()),
+            commit_config: CommitConfig::default(),
+            first_parent: false,
+            credentials,
+            known_hosts,
+        })
     }
 
     pub fn pull(
@@ -86,28 +241,47 @@ impl Repo {
         GitConfig {
             branch,
             gates_branch,
-            private_key,
+            credentials,
+            known_hosts,
             ..
         }: GitConfig,
     ) -> Result<()> {
-        let callbacks = remote_callbacks(private_key);
-        let mut fo = git2::FetchOptions::new();
-        fo.remote_callbacks(callbacks);
-        let mut remote = self.inner.find_remote("origin")?;
         let mut branches = vec![branch.clone()];
         if let Some(gates) = gates_branch {
             branches.push(gates);
         }
-        remote.fetch(&branches, Some(&mut fo), None)?;
-        let suffix = format!("/{}", branch);
-        let remote_head = remote
-            .list()?
-            .iter()
-            .find(|head| head.name().ends_with(&suffix))
-            .context("Cannot find head")?;
+        let remote_head = if self.uses_git_cli() {
+            git_cli::fetch(
+                &self.workdir()?,
+                &branches,
+                &credentials,
+                known_hosts.as_deref(),
+            )?;
+            self.inner
+                .refname_to_id(&format!("refs/remotes/origin/{}", branch))
+                .context("Cannot find head")?
+        } else {
+            let mut remote = self.inner.find_remote("origin")?;
+            let known_hosts = host_keys(remote.url().unwrap_or_default(), known_hosts)?;
+            let callbacks = remote_callbacks(credentials, known_hosts);
+            let mut fo = git2::FetchOptions::new();
+            fo.remote_callbacks(callbacks);
+            remote
+                .fetch(&branches, Some(&mut fo), None)
+                .map_err(remote_error)?;
+            let suffix = format!("/{}", branch);
+            let remote_head = remote
+                .list()?
+                .iter()
+                .find(|head| head.name().ends_with(&suffix))
+                .context("Cannot find head")?
+                .oid();
+            remote_head
+        };
         let object = self
             .inner
-            .find_object(remote_head.oid(), Some(ObjectType::Commit))?;
+            .find_object(remote_head, Some(ObjectType::Commit))?;
+        self.fetch_missing_blobs(&object.peel_to_tree()?)?;
         self.inner.reset(&object, ResetType::Hard, None)?;
         Ok(())
     }
@@ -116,17 +290,30 @@ impl Repo {
         &self,
         GitConfig {
             branch,
-            private_key,
+            credentials,
+            known_hosts,
             ..
         }: GitConfig,
     ) -> Result<()> {
-        let callbacks = remote_callbacks(private_key.clone());
-        let mut fo = git2::FetchOptions::new();
-        fo.remote_callbacks(callbacks);
         let mut remote = self.inner.find_remote("origin")?;
-        remote
-            .fetch(&[branch.clone()], Some(&mut fo), None)
-            .context("Couldn't fetch origin")?;
+        let url = remote.url().unwrap_or_default().to_string();
+        if self.uses_git_cli() {
+            git_cli::fetch(
+                &self.workdir()?,
+                std::slice::from_ref(&branch),
+                &credentials,
+                known_hosts.as_deref(),
+            )?;
+        } else {
+            let callbacks =
+                remote_callbacks(credentials.clone(), host_keys(&url, known_hosts.clone())?);
+            let mut fo = git2::FetchOptions::new();
+            fo.remote_callbacks(callbacks);
+            remote
+                .fetch(std::slice::from_ref(&branch), Some(&mut fo), None)
+                .map_err(remote_error)
+                .context("Couldn't fetch origin")?;
+        }
 
         let annotated_head = self
             .inner
@@ -153,9 +340,16 @@ impl Repo {
             .reference_to_annotated_commit(&remote_ref)
             .context("Couldn't get remote commit")?;
 
+        let head_ref = head_commit
+            .refname()
+            .context("Couldn't resolve head reference")?
+            .to_string();
+        // Rebase in memory so the rebased commits can be signed and
+        // conflicting state files can be merged
         let mut rebase_options = RebaseOptions::new();
+        rebase_options.inmemory(true);
         let mut merge_options = MergeOptions::new();
-        merge_options.fail_on_conflict(true);
+        merge_options.fail_on_conflict(false);
         rebase_options.merge_options(merge_options);
         let mut rebase = self.inner.rebase(
             Some(&head_commit),
@@ -163,77 +357,442 @@ impl Repo {
             None,
             Some(&mut rebase_options),
         )?;
-        let sig = Signature::now("Cepler", "bot@cepler.dev")?;
-        while let Some(_) = rebase.next() {
-            rebase
-                .commit(None, &sig, None)
+        let committer = self.signature()?;
+        let mut new_head = self.inner.find_commit(remote_commit.id())?;
+        if self.is_partial() {
+            self.fetch_missing_blobs(&new_head.tree()?)?;
+            self.fetch_rebased_blobs(remote_commit.id(), head_commit.id())?;
+        }
+        while let Some(operation) = rebase.next() {
+            let operation =
+                operation.map_err(|e| CeplerError::PushConflict(e.message().to_string()))?;
+            let mut index = rebase.inmemory_index()?;
+            let original = self.inner.find_commit(operation.id())?;
+            self.merge_state_changes(
+                &mut index,
+                &original.parent(0)?.tree()?,
+                &new_head.tree()?,
+                &original.tree()?,
+            )
+            .context(format!(
+                "Commit '{}' conflicts with origin/{}",
+                operation.id(),
+                branch
+            ))?;
+            let tree = self.inner.find_tree(index.write_tree_to(&self.inner)?)?;
+            let oid = self
+                .create_commit(
+                    false,
+                    &original.author(),
+                    &committer,
+                    original.message().unwrap_or_default(),
+                    &tree,
+                    &[&new_head],
+                )
                 .context("Couldn't commit rebase")?;
+            new_head = self.inner.find_commit(oid)?;
         }
         rebase.finish(None).context("Couldn't finish rebase")?;
+        // Checkout before moving the branch so files are compared against the old head
+        self.inner.checkout_tree(new_head.as_object(), None)?;
+        self.inner.reference(
+            &head_ref,
+            new_head.id(),
+            true,
+            &format!("cepler: rebase onto origin/{}", branch),
+        )?;
+        self.inner.set_head(&head_ref)?;
 
+        let refspec = format!("{}:{}", head_ref, head_ref);
+        if self.uses_git_cli() {
+            return git_cli::push(
+                &self.workdir()?,
+                &refspec,
+                &credentials,
+                known_hosts.as_deref(),
+            );
+        }
+        // Rejections by the remote are only reported via this callback
+        let rejected = Rc::new(RefCell::new(None));
+        let mut callbacks = remote_callbacks(credentials, host_keys(&url, known_hosts)?);
+        let rejection = Rc::clone(&rejected);
+        callbacks.push_update_reference(move |refname, status| {
+            if let Some(status) = status {
+                *rejection.borrow_mut() = Some(format!("'{}' was rejected: {}", refname, status));
+            }
+            Result::Ok(())
+        });
         let mut push_options = PushOptions::new();
-        push_options.remote_callbacks(remote_callbacks(private_key));
+        push_options.remote_callbacks(callbacks);
         remote
-            .push(
-                &[format!(
-                    "{}:{}",
-                    head_commit.refname().unwrap(),
-                    head_commit.refname().unwrap(),
-                )],
-                Some(&mut push_options),
-            )
+            .push(&[refspec], Some(&mut push_options))
+            .map_err(remote_error)
             .context("Couldn't push to remote")?;
+        if let Some(rejection) = rejected.borrow_mut().take() {
+            return Err(CeplerError::PushConflict(rejection).into());
+        }
         Ok(())
     }
 
-    pub fn open(gate: Option<String>) -> Result<Self> {
-        let inner = Repository::open_from_env()?;
-        let gate = if let Some(gate) = gate {
-            let commit = Oid::from_str(&gate).context("Gate is not a valid commit hash")?;
-            inner
-                .find_commit(commit)
-                .context("Gate commit doesn't exist")?;
+    pub fn open(dir: &Path, gate: Option<String>) -> Result<Self> {
+        let inner = Repository::discover(dir)?;
+        let mut repo = Self {
+            inner,
+            gate: None,
+            last_changed: RefCell::new(HashMap::new()),
+            commit_config: CommitConfig::default(),
+            first_parent: false,
+            credentials: GitCredentials::default(),
+            known_hosts: None,
+        };
+        repo.set_gate(gate)?;
+        Ok(repo)
+    }
+
+    /// Sets the identity and signing key of the commits created by cepler.
+    pub fn set_commit_config(&mut self, config: CommitConfig) {
+        self.commit_config = config;
+    }
+
+    /// Sets the credentials and known_hosts used to fetch submodules
+    /// and the history and objects missing from shallow and partial clones.
+    pub fn set_credentials(&mut self, credentials: GitCredentials, known_hosts: Option<String>) {
+        self.credentials = credentials;
+        self.known_hosts = known_hosts;
+    }
+
+    /// Only follow the first parent of merge commits when walking the history.
+    pub fn set_first_parent(&mut self, first_parent: bool) {
+        if self.first_parent != first_parent {
+            self.last_changed.borrow_mut().clear();
+        }
+        self.first_parent = first_parent;
+    }
+
+    /// Changes the gate while keeping the cached history of the repository.
+    pub fn set_gate(&mut self, gate: Option<String>) -> Result<()> {
+        self.gate = if let Some(gate) = gate {
+            let commit = Oid::from_str(&gate).map_err(|_| {
+                CeplerError::GateInvalid(format!("'{}' is not a valid commit hash", gate))
+            })?;
+            self.inner.find_commit(commit).map_err(|_| {
+                CeplerError::GateInvalid(format!("Gate commit '{}' doesn't exist", gate))
+            })?;
             Some(commit)
         } else {
             None
         };
-        Ok(Self { inner, gate })
+        Ok(())
     }
 
-    pub fn commit_state_file(&self, scope: &str, file_name: String) -> Result<()> {
-        let path = Path::new(&file_name);
-        let mut index = self.inner.index()?;
-        index.add_path(path)?;
-        let oid = index.write_tree()?;
-        let tree = self.inner.find_tree(oid)?;
-        let sig = Signature::now("Cepler", "bot@cepler.io")?;
+    pub fn workdir(&self) -> Result<PathBuf> {
+        self.inner
+            .workdir()
+            .map(Path::to_path_buf)
+            .context("Repository has no working directory")
+    }
 
-        let head_commit = self.inner.head().unwrap().peel_to_commit().unwrap();
-        let msg = if scope != default_scope() {
+    /// The git directory shared by all worktrees of the repository.
+    /// Linked worktrees point to it from their own git directory.
+    fn common_dir(&self) -> PathBuf {
+        let git_dir = self.inner.path();
+        match std::fs::read_to_string(git_dir.join("commondir")) {
+            Result::Ok(common_dir) => git_dir.join(common_dir.trim_end()),
+            Err(_) => git_dir.to_path_buf(),
+        }
+    }
+
+    /// Commits the state file with `message` or a default message.
+    pub fn commit_state_file(
+        &self,
+        scope: &str,
+        file_name: String,
+        message: Option<String>,
+    ) -> Result<()> {
+        let path = Path::new(&file_name);
+        let msg = if let Some(message) = message {
+            message
+        } else if scope != default_scope() {
             format!(
                 "[cepler] Updated '{}' state in '{}'",
                 scope,
-                path.file_stem().unwrap().to_str().unwrap()
+                file_stem(path)?
             )
         } else {
-            format!(
-                "[cepler] Updated '{}' state",
-                path.file_stem().unwrap().to_str().unwrap()
-            )
+            format!("[cepler] Updated '{}' state", file_stem(path)?)
         };
+        self.commit_file(path, &msg)
+    }
+
+    pub fn commit_lock_file(&self, scope: &str, file_name: String) -> Result<()> {
+        let path = Path::new(&file_name);
+        let action = if self.workdir()?.join(path).exists() {
+            "Locked"
+        } else {
+            "Unlocked"
+        };
+        let msg = if scope != default_scope() {
+            format!("[cepler] {} '{}' in '{}'", action, file_stem(path)?, scope)
+        } else {
+            format!("[cepler] {} '{}'", action, file_stem(path)?)
+        };
+        self.commit_file(path, &msg)
+    }
+
+    fn commit_file(&self, path: &Path, msg: &str) -> Result<()> {
+        let exists = self.workdir()?.join(path).exists();
+        let mut index = self.inner.index()?;
+        if exists {
+            index.add_path(path)?;
+        } else {
+            index.remove_path(path)?;
+        }
+        let oid = index.write_tree()?;
+        let tree = self.inner.find_tree(oid)?;
+        let sig = self.signature()?;
+
+        let head_commit = self.head_commit()?;
+        self.create_commit(true, &sig, &sig, msg, &tree, &[&head_commit])
+            .context(format!("Couldn't commit '{}'", path.display()))?;
+        if exists {
+            let mut checkout = CheckoutBuilder::new();
+            checkout.path(path);
+            self.inner.checkout_index(None, Some(&mut checkout))?;
+        } else {
+            index.write()?;
+        }
+        Ok(())
+    }
+
+    /// Shallow and partial clones are fetched via the git cli.
+    fn uses_git_cli(&self) -> bool {
+        self.inner.is_shallow() || self.is_partial()
+    }
+
+    /// Returns true if objects may be missing because the repository is a partial clone.
+    fn is_partial(&self) -> bool {
         self.inner
-            .commit(Some("HEAD"), &sig, &sig, &msg, &tree, &[&head_commit])?;
-        let mut checkout = CheckoutBuilder::new();
-        checkout.path(path);
-        self.inner.checkout_index(None, Some(&mut checkout))?;
+            .config()
+            .and_then(|config| config.get_bool("remote.origin.promisor"))
+            .unwrap_or(false)
+    }
+
+    /// Fetches the blobs missing from a partial clone that are needed to check out `tree`.
+    fn fetch_missing_blobs(&self, tree: &Tree) -> Result<()> {
+        if !self.is_partial() {
+            return Ok(());
+        }
+        let mut oids = Vec::new();
+        tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+            if entry.kind() == Some(ObjectType::Blob) {
+                oids.push(entry.id());
+            }
+            TreeWalkResult::Ok
+        })?;
+        self.fetch_missing(oids)
+    }
+
+    /// Fetches the blobs changed by the commits that are about to be rebased
+    /// onto `upstream` as merging them needs their previous content.
+    fn fetch_rebased_blobs(&self, upstream: Oid, head: Oid) -> Result<()> {
+        let mut walk = self.inner.revwalk()?;
+        walk.push(head)?;
+        walk.hide(upstream)?;
+        let mut oids = Vec::new();
+        for oid in walk {
+            let commit = self.inner.find_commit(oid?)?;
+            let parent_tree = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+            let diff =
+                self.inner
+                    .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+            for delta in diff.deltas() {
+                oids.push(delta.old_file().id());
+                oids.push(delta.new_file().id());
+            }
+        }
+        self.fetch_missing(oids)
+    }
+
+    fn fetch_missing(&self, oids: impl IntoIterator<Item = Oid>) -> Result<()> {
+        if !self.is_partial() {
+            return Ok(());
+        }
+        let odb = self.inner.odb()?;
+        let mut missing: Vec<_> = oids
+            .into_iter()
+            .filter(|oid| !oid.is_zero() && !odb.exists(*oid))
+            .collect();
+        missing.sort();
+        missing.dedup();
+        git_cli::fetch_objects(
+            &self.workdir()?,
+            &missing,
+            &self.credentials,
+            self.known_hosts.as_deref(),
+        )
+    }
+
+    /// Fetches more history into a shallow clone.
+    fn deepen(&self) -> Result<()> {
+        git_cli::deepen(
+            &self.workdir()?,
+            DEEPEN_BY,
+            &self.credentials,
+            self.known_hosts.as_deref(),
+        )
+    }
+
+    /// Returns the parents of `commit` (only the first one in first parent mode),
+    /// deepening a shallow clone if it ends at `commit`.
+    fn parents<'r>(&'r self, commit: &Commit<'r>) -> Result<Vec<Commit<'r>>> {
+        let count = if self.first_parent {
+            commit.parent_count().min(1)
+        } else {
+            commit.parent_count()
+        };
+        let parents: Vec<_> = commit.parents().take(count).collect();
+        if parents.len() == count || !self.inner.is_shallow() {
+            return Ok(parents);
+        }
+        self.deepen()?;
+        let mut parents = Vec::new();
+        for id in commit.parent_ids().take(count) {
+            parents.push(
+                self.inner
+                    .find_commit(id)
+                    .context(format!("Parent '{}' missing after deepening", id))?,
+            );
+        }
+        Ok(parents)
+    }
+
+    /// Merges the `.state` files that both `upstream` and `local` changed since `base`
+    /// via a semantic three-way merge, even if git merged their text cleanly.
+    /// Any other conflict fails the push.
+    fn merge_state_changes(
+        &self,
+        index: &mut Index,
+        base: &Tree,
+        upstream: &Tree,
+        local: &Tree,
+    ) -> Result<()> {
+        for conflict in index.conflicts()? {
+            let conflict = conflict?;
+            let path = conflict
+                .our
+                .as_ref()
+                .or(conflict.their.as_ref())
+                .or(conflict.ancestor.as_ref())
+                .map(|entry| String::from_utf8_lossy(&entry.path).to_string())
+                .unwrap_or_default();
+            if conflict.our.is_none() || conflict.their.is_none() {
+                return Err(CeplerError::PushConflict(format!(
+                    "'{}' was removed concurrently",
+                    path
+                ))
+                .into());
+            }
+            if !database::is_state_file(Path::new(&path)) {
+                return Err(CeplerError::PushConflict(format!("'{}' conflicts", path)).into());
+            }
+        }
+        let diff = self
+            .inner
+            .diff_tree_to_tree(Some(base), Some(local), None)?;
+        let paths: Vec<PathBuf> = diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().map(Path::to_path_buf))
+            .filter(|path| database::is_state_file(path))
+            .collect();
+        for path in paths {
+            let id = |tree: &Tree| tree.get_path(&path).ok().map(|entry| entry.id());
+            let base_id = id(base);
+            let (upstream_id, local_id) = match (id(upstream), id(local)) {
+                (Some(upstream_id), Some(local_id))
+                    if upstream_id != local_id && base_id != Some(upstream_id) =>
+                {
+                    (upstream_id, local_id)
+                }
+                _ => continue,
+            };
+            self.fetch_missing(base_id.into_iter().chain([upstream_id, local_id]))?;
+            let content =
+                |oid: Oid| -> Result<Vec<u8>> { Ok(self.inner.find_blob(oid)?.content().to_vec()) };
+            let base = base_id.map(content).transpose()?;
+            let merged = database::merge_state_files(
+                base.as_deref(),
+                &content(upstream_id)?,
+                &content(local_id)?,
+            )
+            .context(format!("Couldn't merge '{}'", path.display()))?;
+            // Conflicting files only have the local version at stage 3
+            let mut entry = index
+                .get_path(&path, 0)
+                .or_else(|| index.get_path(&path, 3))
+                .context(format!("'{}' is missing from the index", path.display()))?;
+            index.remove_path(&path)?;
+            entry.id = self.inner.blob(&merged)?;
+            entry.file_size = merged.len() as u32;
+            // Clear the conflict stage so the merged file is added as resolved
+            entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+            index.add(&entry)?;
+        }
         Ok(())
     }
 
-    fn gate_files_matching<'a>(
+    /// Creates a commit that is signed if a signing key is configured.
+    fn create_commit(
+        &self,
+        update_head: bool,
+        author: &Signature,
+        committer: &Signature,
+        msg: &str,
+        tree: &Tree,
+        parents: &[&Commit],
+    ) -> Result<Oid> {
+        let key = match self.commit_config.signing_key.as_ref() {
+            Some(key) => key,
+            None => {
+                let update_ref = if update_head { Some("HEAD") } else { None };
+                return Ok(self
+                    .inner
+                    .commit(update_ref, author, committer, msg, tree, parents)?);
+            }
+        };
+        let buffer = self
+            .inner
+            .commit_create_buffer(author, committer, msg, tree, parents)?;
+        let content = buffer.as_str().context("Commit is not valid utf-8")?;
+        let signature = signing::sign(content, key, self.commit_config.signing_format)?;
+        let oid = self.inner.commit_signed(content, &signature, None)?;
+        if update_head {
+            // commit_signed doesn't update any references
+            self.inner.head()?.set_target(oid, msg)?;
+        }
+        Ok(oid)
+    }
+
+    fn signature(&self) -> Result<Signature<'static>> {
+        Ok(Signature::now(
+            self.commit_config
+                .author_name
+                .as_deref()
+                .unwrap_or(DEFAULT_AUTHOR_NAME),
+            self.commit_config
+                .author_email
+                .as_deref()
+                .unwrap_or(DEFAULT_AUTHOR_EMAIL),
+        )?)
+    }
+
+    fn gate_files_matching(
         &self,
-        globs: &'a [Pattern],
-        ignore_files: &'a [Pattern],
-    ) -> impl Iterator<Item = PathBuf> + 'a {
+        globs: &[Pattern],
+        ignore_files: &[Pattern],
+    ) -> Result<Vec<(PathBuf, FileHash, FileMode)>> {
         let ignore = move |file: &Path| {
             ignore_files
                 .iter()
@@ -245,29 +804,35 @@ impl Repo {
                 .any(|p| p.matches_path_with(file, MATCH_OPTIONS))
         };
         let mut paths = Vec::new();
-        self.all_files(self.gate_commit_hash(), |_, path| {
+        self.all_files(self.gate_commit_hash()?, |hash, mode, path| {
             if !ignore(path) && includes(path) {
-                paths.push(path.to_path_buf())
+                paths.push((path.to_path_buf(), hash, mode))
             }
             Ok(())
         })
-        .expect("Couldn't list gate files");
-        paths.into_iter()
+        .context("Couldn't list gate files")?;
+        Ok(paths)
     }
 
     pub fn all_files<F>(&self, commit: CommitHash, mut f: F) -> Result<()>
     where
-        F: FnMut(FileHash, &Path) -> Result<()>,
+        F: FnMut(FileHash, FileMode, &Path) -> Result<()>,
     {
-        let commit = Oid::from_str(&commit.0).expect("Couldn't parse commit hash");
-        let commit = self.inner.find_commit(commit)?;
+        let commit = self.find_commit(&commit)?;
         let tree = commit.tree().context("Couldn't resolve tree")?;
         let mut ret = Ok(());
         tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
-            let path_name = format!("{}{}", dir, entry.name().expect("Entry has no name"));
+            let name = if let Some(name) = entry.name() {
+                name
+            } else {
+                ret = Err(anyhow!("Tree entry in '{}' has a non utf-8 name", dir));
+                return TreeWalkResult::Abort;
+            };
+            let path_name = format!("{}{}", dir, name);
             let path = Path::new(&path_name);
-            if let Some(ObjectType::Blob) = entry.kind() {
-                if let Err(e) = f(FileHash(entry.id().to_string()), path) {
+            if let Some(ObjectType::Blob) | Some(ObjectType::Commit) = entry.kind() {
+                let mode = FileMode::from_git(entry.filemode());
+                if let Err(e) = f(FileHash(entry.id().to_string()), mode, path) {
                     ret = Err(e);
                     return TreeWalkResult::Abort;
                 }
@@ -277,42 +842,66 @@ impl Repo {
         ret
     }
 
-    fn is_trackable_file(&self, file: &Path) -> bool {
+    fn is_trackable_file(&self, file: &Path) -> Result<bool> {
         if self.inner.status_file(file).is_err() {
-            return false;
+            return Ok(false);
         }
-        !self
-            .inner
-            .status_should_ignore(file)
-            .expect("Cannot check ignore status")
+        Ok(!self.inner.status_should_ignore(file).context(format!(
+            "Couldn't check ignore status of '{}'",
+            file.display()
+        ))?)
     }
 
-    pub fn gate_commit_hash(&self) -> CommitHash {
-        CommitHash(self.gate_oid().to_string())
+    pub fn gate_commit_hash(&self) -> Result<CommitHash> {
+        Ok(CommitHash(self.gate_commit()?.id().to_string()))
     }
 
     pub fn head_commit_summary(&self) -> Result<(CommitHash, String)> {
-        let commit = self.inner.head().unwrap().peel_to_commit().unwrap();
-        Ok((
-            CommitHash(commit.id().to_string()),
-            commit.summary().expect("Couldn't get summary").to_string(),
-        ))
+        let commit = self.head_commit()?;
+        Ok((CommitHash(commit.id().to_string()), summary(&commit)))
     }
 
     pub fn checkout_file_from(&self, path: &str, commit: &CommitHash) -> Result<()> {
-        let object = self.inner.find_object(
-            Oid::from_str(&commit.0).expect("Couldn't parse Oid"),
-            Some(ObjectType::Commit),
-        )?;
+        let commit = self.find_commit(commit)?;
+        if let Ok(entry) = commit.tree()?.get_path(Path::new(path)) {
+            if entry.kind() == Some(ObjectType::Commit) {
+                return self.update_submodule(Path::new(path), entry.id());
+            }
+        }
+        if let Ok(entry) = commit.tree()?.get_path(Path::new(path)) {
+            self.fetch_missing(Some(entry.id()))?;
+        }
+        let object = commit.into_object();
         let mut checkout = CheckoutBuilder::new();
         checkout.force();
         checkout.path(path);
         checkout.update_index(false);
         self.inner.checkout_tree(&object, Some(&mut checkout))?;
+        lfs::materialize(&self.common_dir(), &self.workdir()?.join(path))?;
 
         Ok(())
     }
 
+    /// Returns the LFS pointer stored in the blob `hash` if there is one.
+    pub fn lfs_pointer(&self, hash: &FileHash) -> Result<Option<LfsPointer>> {
+        let oid = Oid::from_str(&hash.0)?;
+        // Gitlinks point to commits that aren't part of this repository
+        let (size, kind) = match self.inner.odb()?.read_header(oid) {
+            Result::Ok(header) => header,
+            Err(_) => return Ok(None),
+        };
+        if kind != ObjectType::Blob || size > lfs::MAX_POINTER_SIZE {
+            return Ok(None);
+        }
+        let blob = self.inner.find_blob(oid)?;
+        Ok(LfsPointer::parse(blob.content()))
+    }
+
+    /// Resolves `bytes` via the local LFS store if they are an LFS pointer.
+    pub fn smudge(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        lfs::smudge(&self.common_dir(), bytes)
+    }
+
     pub fn checkout_gate(
         &self,
         globs: &[Pattern],
@@ -323,15 +912,35 @@ impl Repo {
         checkout.force();
         checkout.update_index(false);
         let mut path_added = false;
-        for path in self.gate_files_matching(globs, ignore_files) {
-            path_added = true;
-            checkout.path(path);
+        let mut submodules = Vec::new();
+        let mut files = Vec::new();
+        let mut blobs = Vec::new();
+        for (path, hash, mode) in self.gate_files_matching(globs, ignore_files)? {
+            if mode == FileMode::Submodule {
+                submodules.push((path, hash));
+            } else {
+                path_added = true;
+                checkout.path(&path);
+                blobs.push(Oid::from_str(&hash.0)?);
+                files.push(path);
+            }
         }
+        self.fetch_missing(blobs)?;
 
-        for path in glob("**/*").expect("List all files") {
-            let path = path.expect("Get file");
-            if self.is_trackable_file(&path) {
-                let path = path.as_path();
+        let workdir = self.workdir()?;
+        let all_files = format!(
+            "{}/**/*",
+            Pattern::escape(
+                workdir
+                    .to_str()
+                    .context("Working directory is not valid utf-8")?
+                    .trim_end_matches('/')
+            )
+        );
+        for full_path in glob(&all_files).context("Couldn't list files")? {
+            let full_path = full_path.context("Couldn't list files")?;
+            let path = full_path.strip_prefix(&workdir)?;
+            if self.is_trackable_file(path)? {
                 let check = |p: &glob::Pattern| {
                     p.matches_path_with(
                         path,
@@ -342,19 +951,72 @@ impl Repo {
                         },
                     )
                 };
-                if !ignore_files.iter().any(|p| check(p))
-                    && path.is_file()
-                    && (clean || globs.iter().any(|p| check(p)))
+                if !ignore_files.iter().any(&check)
+                    && full_path.is_file()
+                    && (clean || globs.iter().any(check))
                 {
-                    std::fs::remove_file(path).expect("Couldn't remove file");
+                    std::fs::remove_file(&full_path)
+                        .context(format!("Couldn't remove file '{}'", full_path.display()))?;
                 }
             }
         }
         if path_added {
             self.inner
-                .checkout_tree(&self.gate_object(), Some(&mut checkout))
-                .expect("Couldn't checkout");
+                .checkout_tree(&self.gate_object()?, Some(&mut checkout))
+                .context("Couldn't checkout gate")?;
         }
+        for path in files {
+            lfs::materialize(&self.common_dir(), &workdir.join(path))?;
+        }
+        for (path, hash) in submodules {
+            self.update_submodule(&path, Oid::from_str(&hash.0)?)?;
+        }
+        Ok(())
+    }
+
+    /// Checks out `commit` in the submodule at `path`, cloning it if necessary.
+    fn update_submodule(&self, path: &Path, commit: Oid) -> Result<()> {
+        let name = path
+            .to_str()
+            .context(format!("Path '{}' is not valid utf-8", path.display()))?;
+        let mut submodule = self
+            .inner
+            .find_submodule(name)
+            .context(format!("Couldn't find submodule '{}'", name))?;
+        let url = submodule.url().unwrap_or_default().to_string();
+        let fetch_options = || -> Result<git2::FetchOptions<'static>> {
+            let mut fo = git2::FetchOptions::new();
+            fo.remote_callbacks(remote_callbacks(
+                self.credentials.clone(),
+                host_keys(&url, self.known_hosts.clone())?,
+            ));
+            Ok(fo)
+        };
+        let repo = match submodule.open() {
+            Result::Ok(repo) => repo,
+            Err(_) => {
+                let mut update = git2::SubmoduleUpdateOptions::new();
+                update.fetch(fetch_options()?);
+                submodule
+                    .update(true, Some(&mut update))
+                    .map_err(remote_error)
+                    .context(format!("Couldn't clone submodule '{}'", name))?;
+                submodule.open()?
+            }
+        };
+        if repo.find_commit(commit).is_err() {
+            repo.find_remote("origin")?
+                .fetch::<&str>(&[], Some(&mut fetch_options()?), None)
+                .map_err(remote_error)
+                .context(format!("Couldn't fetch submodule '{}'", name))?;
+        }
+        repo.set_head_detached(commit).context(format!(
+            "Commit '{}' not found in submodule '{}'",
+            commit, name
+        ))?;
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        repo.checkout_head(Some(&mut checkout))?;
         Ok(())
     }
 
@@ -370,25 +1032,20 @@ impl Repo {
     where
         F: FnMut(CommitHash) -> Result<bool>,
     {
-        let commit = Oid::from_str(&commit.0).expect("Couldn't parse commit hash");
-        let commit = self.inner.find_commit(commit)?;
+        let commit = self.find_commit(&commit)?;
         let mut set = HashSet::new();
         let mut queue = VecDeque::new();
         set.insert(commit.id());
-        for parent in commit.parents() {
+        for parent in self.parents(&commit)? {
             if set.insert(parent.id()) {
                 queue.push_back(parent);
             }
         }
-        loop {
-            if queue.is_empty() {
-                break;
-            }
-            let commit = queue.pop_front().unwrap();
+        while let Some(commit) = queue.pop_front() {
             if !cb(CommitHash(commit.id().to_string()))? {
                 break;
             }
-            for parent in commit.parents() {
+            for parent in self.parents(&commit)? {
                 if set.insert(parent.id()) {
                     queue.push_back(parent);
                 }
@@ -397,13 +1054,59 @@ impl Repo {
         Ok(())
     }
 
+    /// Number of commits reachable from `to` but not from `from`.
+    pub fn commits_between(&self, from: &CommitHash, to: &CommitHash) -> Result<usize> {
+        let mut walk = self.inner.revwalk()?;
+        if self.first_parent {
+            walk.simplify_first_parent()?;
+        }
+        walk.push(Oid::from_str(&to.0).context("Couldn't parse commit hash")?)?;
+        walk.hide(Oid::from_str(&from.0).context("Couldn't parse commit hash")?)?;
+        let mut count = 0;
+        for oid in walk {
+            oid?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Commit time (seconds since the epoch) of the last commit on HEAD that changed `file`.
+    /// Returns `None` if `file` isn't committed.
+    pub fn last_change_time(&self, file: &Path) -> Result<Option<i64>> {
+        let head = self.head_commit()?;
+        if head.tree()?.get_path(file).is_err() {
+            return Ok(None);
+        }
+        let (commit, _) = self.find_last_changed_commit(file, CommitHash(head.id().to_string()))?;
+        Ok(Some(self.commit_time(&commit)?))
+    }
+
+    /// Time (seconds since the epoch) `commit` was committed.
+    pub fn commit_time(&self, commit: &CommitHash) -> Result<i64> {
+        Ok(self.find_commit(commit)?.time().seconds())
+    }
+
+    /// Time (seconds since the epoch) `commit` was authored.
+    pub fn author_time(&self, commit: &CommitHash) -> Result<i64> {
+        Ok(self.find_commit(commit)?.author().when().seconds())
+    }
+
+    pub fn is_ancestor(&self, ancestor: &CommitHash, commit: &CommitHash) -> Result<bool> {
+        let ancestor = Oid::from_str(&ancestor.0).context("Couldn't parse commit hash")?;
+        let commit = Oid::from_str(&commit.0).context("Couldn't parse commit hash")?;
+        Ok(ancestor == commit || self.inner.graph_descendant_of(commit, ancestor)?)
+    }
+
     pub fn find_last_changed_commit(
         &self,
         file: &Path,
         from_commit: CommitHash,
     ) -> Result<(CommitHash, String)> {
-        let commit = Oid::from_str(&from_commit.0).expect("Couldn't parse commit hash");
-        let commit = self.inner.find_commit(commit)?;
+        let commit = self.find_commit(&from_commit)?;
+        let key = (commit.id(), file.to_path_buf());
+        if let Some(found) = self.last_changed.borrow().get(&key) {
+            return Ok(found.clone());
+        }
         let target = commit
             .tree()
             .context("Couldn't resolve tree")?
@@ -414,12 +1117,15 @@ impl Repo {
         set.insert(commit.id());
         queue.push_back(commit);
 
-        loop {
-            let commit = queue.pop_front().unwrap();
+        while let Some(commit) = queue.pop_front() {
             let mut go = false;
-            for parent in commit.parents() {
-                if let Ok(tree) = parent.tree().expect("Couldn't get tree").get_path(file) {
-                    let eq = tree.id() == target.id();
+            for parent in self.parents(&commit)? {
+                if let Ok(tree) = parent
+                    .tree()
+                    .context("Couldn't resolve tree")?
+                    .get_path(file)
+                {
+                    let eq = tree.id() == target.id() && tree.filemode() == target.filemode();
                     if eq && set.insert(parent.id()) {
                         queue.push_back(parent);
                     }
@@ -427,23 +1133,51 @@ impl Repo {
                 }
             }
             if !go || queue.is_empty() {
-                return Ok((
-                    CommitHash(commit.id().to_string()),
-                    commit.summary().expect("Couldn't get summary").to_string(),
-                ));
+                let found = (CommitHash(commit.id().to_string()), summary(&commit));
+                self.last_changed.borrow_mut().insert(key, found.clone());
+                return Ok(found);
             }
         }
+        unreachable!("queue always contains the starting commit")
     }
 
     pub fn get_file_content<F, T>(&self, commit: CommitHash, file: &Path, f: F) -> Result<Option<T>>
     where
         F: Fn(&[u8]) -> Result<T>,
     {
-        let commit = Oid::from_str(&commit.0).expect("Couldn't parse commit hash");
-        let commit = self.inner.find_commit(commit)?;
+        let commit = self.find_commit(&commit)?;
         self.get_file_from_commit(commit, file, f)
     }
 
+    /// Renders a unified diff of `file` between the blobs at `old` and `new`.
+    /// A missing commit or file is treated as empty content.
+    pub fn diff_file_content(
+        &self,
+        file: &Path,
+        old: Option<&CommitHash>,
+        new: Option<&CommitHash>,
+    ) -> Result<String> {
+        let content = |commit: Option<&CommitHash>| -> Result<Option<Vec<u8>>> {
+            if let Some(commit) = commit {
+                self.get_file_content(commit.clone(), file, |bytes| Ok(bytes.to_vec()))
+            } else {
+                Ok(None)
+            }
+        };
+        let old_content = content(old)?;
+        let new_content = content(new)?;
+        let mut patch = Patch::from_buffers(
+            old_content.as_deref().unwrap_or_default(),
+            old_content.as_ref().map(|_| file),
+            new_content.as_deref().unwrap_or_default(),
+            new_content.as_ref().map(|_| file),
+            None,
+        )
+        .context("Couldn't create diff")?;
+        let buf = patch.to_buf().context("Couldn't render diff")?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
     fn get_file_from_commit<F, T>(&self, commit: Commit, file: &Path, f: F) -> Result<Option<T>>
     where
         F: Fn(&[u8]) -> Result<T>,
@@ -454,6 +1188,11 @@ impl Repo {
         } else {
             return Ok(None);
         };
+        if target.kind() == Some(ObjectType::Commit) {
+            let content = format!("Subproject commit {}\n", target.id());
+            return Ok(Some(f(content.as_bytes())?));
+        }
+        self.fetch_missing(Some(target.id()))?;
         let object = target
             .to_object(&self.inner)
             .context("Couldn't create object")?;
@@ -461,22 +1200,56 @@ impl Repo {
         Ok(Some(f(blob.content())?))
     }
 
-    fn gate_commit(&self) -> Commit<'_> {
-        if let Some(gate) = self.gate {
-            self.inner.find_commit(gate).unwrap()
-        } else {
-            self.inner.head().unwrap().peel_to_commit().unwrap()
+    fn find_commit(&self, commit: &CommitHash) -> Result<Commit<'_>> {
+        let oid = Oid::from_str(&commit.0)
+            .context(format!("Couldn't parse commit hash '{}'", commit.0))?;
+        let mut rounds = 0;
+        loop {
+            match self.inner.find_commit(oid) {
+                // Commits just past the fetched history are found by deepening, after a few
+                // rounds the rest is fetched at once so that bogus hashes fail quickly
+                Err(e)
+                    if e.code() == ErrorCode::NotFound
+                        && self.inner.is_shallow()
+                        && rounds <= MAX_DEEPEN_ROUNDS =>
+                {
+                    if rounds < MAX_DEEPEN_ROUNDS {
+                        self.deepen()?;
+                    } else {
+                        git_cli::unshallow(
+                            &self.workdir()?,
+                            &self.credentials,
+                            self.known_hosts.as_deref(),
+                        )?;
+                    }
+                    rounds += 1;
+                }
+                ret => {
+                    return ret.context(format!("Commit '{}' not found in repository", commit.0))
+                }
+            }
         }
     }
 
-    fn gate_oid(&self) -> Oid {
-        self.gate_commit().id()
+    fn head_commit(&self) -> Result<Commit<'_>> {
+        self.inner
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .context("Couldn't resolve HEAD - does the repository have any commits?")
     }
 
-    fn gate_object(&self) -> Object {
-        self.inner
-            .find_object(self.gate_oid(), Some(ObjectType::Commit))
-            .unwrap()
+    fn gate_commit(&self) -> Result<Commit<'_>> {
+        if let Some(gate) = self.gate {
+            self.inner
+                .find_commit(gate)
+                .context(format!("Gate commit '{}' not found", gate))
+        } else {
+            self.head_commit()
+        }
+    }
+
+    fn gate_object(&self) -> Result<Object<'_>> {
+        Ok(self.gate_commit()?.into_object())
     }
 
     pub fn get_file_from_branch<F, T>(&self, name: &str, file: &Path, f: F) -> Result<Option<T>>
@@ -495,10 +1268,87 @@ impl Repo {
     }
 }
 
-fn remote_callbacks(key: String) -> RemoteCallbacks<'static> {
+/// libgit2 keeps asking for credentials until the remote accepts them.
+const MAX_CREDENTIAL_ATTEMPTS: usize = 3;
+
+/// Parses `known_hosts` if it applies to `url` and returns it along with the port of `url`.
+/// Https remotes are verified via their certificate instead.
+fn host_keys(url: &str, known_hosts: Option<String>) -> Result<Option<(KnownHosts, u16)>> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        return Ok(None);
+    }
+    known_hosts
+        .map(|known_hosts| Ok((KnownHosts::parse(&known_hosts)?, known_hosts::ssh_port(url))))
+        .transpose()
+}
+
+fn remote_callbacks(
+    credentials: GitCredentials,
+    known_hosts: Option<(KnownHosts, u16)>,
+) -> RemoteCallbacks<'static> {
     let mut callbacks = RemoteCallbacks::new();
-    callbacks.credentials(move |_url, username_from_url, _allowed_types| {
-        Cred::ssh_key_from_memory(username_from_url.unwrap(), None, &key, None)
+    if let Some((known_hosts, port)) = known_hosts {
+        callbacks.certificate_check(move |cert, host| {
+            match cert.as_hostkey().and_then(|key| key.hash_sha256()) {
+                Some(hash) => known_hosts.verify(host, port, hash),
+                None => false,
+            }
+        });
+    }
+    let mut attempts = 0;
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        attempts += 1;
+        if attempts > MAX_CREDENTIAL_ATTEMPTS {
+            return Err(git2::Error::new(
+                ErrorCode::Auth,
+                ErrorClass::Net,
+                format!("Authentication for '{}' failed", url),
+            ));
+        }
+        let username = credentials
+            .username
+            .as_deref()
+            .or(username_from_url)
+            .unwrap_or("git");
+        if allowed_types.contains(CredentialType::USERNAME) {
+            return Cred::username(username);
+        }
+        if allowed_types.contains(CredentialType::SSH_KEY) {
+            let passphrase = credentials.passphrase.as_deref();
+            return if let Some(key) = credentials.private_key.as_ref() {
+                Cred::ssh_key_from_memory(username, None, key, passphrase)
+            } else if let Some(file) = credentials.private_key_file.as_ref() {
+                Cred::ssh_key(username, None, Path::new(file), passphrase)
+            } else {
+                Cred::ssh_key_from_agent(username)
+            };
+        }
+        if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(password) = credentials.password.as_ref() {
+                return Cred::userpass_plaintext(username, password);
+            }
+            let config = git2::Config::open_default()?;
+            return Cred::credential_helper(
+                &config,
+                url,
+                credentials.username.as_deref().or(username_from_url),
+            );
+        }
+        Err(git2::Error::new(
+            ErrorCode::Auth,
+            ErrorClass::Net,
+            format!("No supported credentials for '{}'", url),
+        ))
     });
     callbacks
 }
+
+fn file_stem(path: &Path) -> Result<&str> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .context(format!("Couldn't determine name of '{}'", path.display()))
+}
+
+fn summary(commit: &Commit) -> String {
+    commit.summary().unwrap_or_default().to_string()
+}
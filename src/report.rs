@@ -0,0 +1,130 @@
+use super::{config::*, repo::*, workspace::Workspace};
+use anyhow::*;
+use serde::Serialize;
+use std::{collections::HashSet, path::Path};
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// How long changes took to be recorded in an environment and how often it was deployed.
+#[derive(Debug, Serialize)]
+pub struct LeadTime {
+    pub deployment: String,
+    pub environment: String,
+    /// Number of distinct states recorded.
+    pub deployments: usize,
+    pub first_record: Option<i64>,
+    pub last_record: Option<i64>,
+    pub deployments_per_day: f64,
+    /// Number of commits that reached the environment.
+    pub changes: usize,
+    pub mean_lead_time_seconds: Option<i64>,
+    pub median_lead_time_seconds: Option<i64>,
+}
+
+/// Computes the lead time of all environments in `configs` (or only those named in `only`).
+/// The lead time of a change is the time between authoring the commit a file
+/// was recorded from and the commit recording it in the environment.
+/// Only commits that reached the environment after its first record are counted.
+/// `credentials` and `known_hosts` are used to fetch history missing from shallow clones.
+pub fn lead_times(
+    workdir: &Path,
+    configs: &[(Config, String)],
+    only: &[&str],
+    credentials: &GitCredentials,
+    known_hosts: Option<String>,
+) -> Result<Vec<LeadTime>> {
+    let mut repo = Repo::open(workdir, None)?;
+    repo.set_credentials(credentials.clone(), known_hosts);
+    let mut ret = Vec::new();
+    for (config, config_path) in configs {
+        repo.set_first_parent(config.first_parent);
+        let ws = Workspace::new(workdir, &config.scope, config_path.clone(), false)?;
+        let mut envs: Vec<_> = config
+            .environments
+            .values()
+            .filter(|env| only.is_empty() || only.contains(&env.name.as_str()))
+            .collect();
+        envs.sort_by(|a, b| a.name.cmp(&b.name));
+        for env in envs {
+            let mut records = Vec::new();
+            for (commit, state) in ws.recorded_states(&repo, env)? {
+                records.push((repo.commit_time(&commit)?, state));
+            }
+            records.sort_by_key(|(time, _)| *time);
+
+            // The files of the first record may have been authored long before
+            // the environment was set up, so only later changes are counted.
+            let mut seen: HashSet<_> = records
+                .first()
+                .map(|(_, state)| {
+                    state
+                        .files
+                        .values()
+                        .map(|file| file.from_commit.clone().inner())
+                        .collect()
+                })
+                .unwrap_or_default();
+            let mut lead_times = Vec::new();
+            for (time, state) in records.iter().skip(1) {
+                for file in state.files.values() {
+                    if seen.insert(file.from_commit.clone().inner()) {
+                        let authored = repo.author_time(&file.from_commit)?;
+                        lead_times.push((time - authored).max(0));
+                    }
+                }
+            }
+            lead_times.sort_unstable();
+
+            let first_record = records.first().map(|(time, _)| *time);
+            let last_record = records.last().map(|(time, _)| *time);
+            let days = match (first_record, last_record) {
+                (Some(first), Some(last)) => ((last - first) as f64 / SECONDS_PER_DAY).max(1.0),
+                _ => 1.0,
+            };
+            ret.push(LeadTime {
+                deployment: config.scope.clone(),
+                environment: env.name.clone(),
+                deployments: records.len(),
+                first_record,
+                last_record,
+                deployments_per_day: records.len() as f64 / days,
+                changes: lead_times.len(),
+                mean_lead_time_seconds: if lead_times.is_empty() {
+                    None
+                } else {
+                    Some(lead_times.iter().sum::<i64>() / lead_times.len() as i64)
+                },
+                median_lead_time_seconds: lead_times.get(lead_times.len() / 2).copied(),
+            });
+        }
+    }
+    Ok(ret)
+}
+
+pub fn to_csv(lead_times: &[LeadTime]) -> String {
+    let mut ret = String::from("deployment,environment,deployments,first_record,last_record,deployments_per_day,changes,mean_lead_time_seconds,median_lead_time_seconds\n");
+    let optional = |value: Option<i64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for lead_time in lead_times {
+        ret.push_str(&format!(
+            "{},{},{},{},{},{:.2},{},{},{}\n",
+            csv_field(&lead_time.deployment),
+            csv_field(&lead_time.environment),
+            lead_time.deployments,
+            optional(lead_time.first_record),
+            optional(lead_time.last_record),
+            lead_time.deployments_per_day,
+            lead_time.changes,
+            optional(lead_time.mean_lead_time_seconds),
+            optional(lead_time.median_lead_time_seconds),
+        ));
+    }
+    ret
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
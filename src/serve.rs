@@ -0,0 +1,209 @@
+use super::{
+    config::*, error::*, events::Event, metrics, repo::GitCredentials, workspace::Workspace,
+};
+use anyhow::*;
+use serde_json::{json, Value};
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Method, Response, Server};
+
+/// Number of states returned by `/history` unless `?limit=` is given.
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+
+/// The configs answered for until the next refresh.
+pub struct Snapshot {
+    pub configs: Vec<(Config, String)>,
+    pub gates: Option<GatesConfig>,
+    /// Used to fetch history missing from shallow clones.
+    pub credentials: GitCredentials,
+    pub known_hosts: Option<String>,
+}
+
+/// Serves read-only JSON endpoints describing the environments of the configs
+/// returned by `refresh`, which is called again every `interval`:
+///
+/// - `/environments`
+/// - `/environments/<deployment>/<environment>`
+/// - `/environments/<deployment>/<environment>/diff`
+/// - `/environments/<deployment>/<environment>/queue`
+/// - `/environments/<deployment>/<environment>/history?limit=<n>`
+/// - `/metrics` (Prometheus text format)
+pub fn serve<F>(
+    listen: &str,
+    workdir: &Path,
+    ignore_queue: bool,
+    interval: Duration,
+    mut refresh: F,
+) -> Result<()>
+where
+    F: FnMut() -> Result<Snapshot>,
+{
+    let server =
+        Server::http(listen).map_err(|e| anyhow!("Couldn't listen on '{}': {}", listen, e))?;
+    let json_type = Header::from_bytes("Content-Type", "application/json")
+        .map_err(|_| anyhow!("Invalid content type header"))?;
+    let metrics_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+        .map_err(|_| anyhow!("Invalid content type header"))?;
+    let mut snapshot = refresh()?;
+    let mut next_refresh = Instant::now() + interval;
+    eprintln!("Serving environments on http://{}", listen);
+    loop {
+        let now = Instant::now();
+        if now >= next_refresh {
+            match refresh() {
+                Result::Ok(new_snapshot) => snapshot = new_snapshot,
+                Err(e) => eprintln!("Couldn't refresh repository: {:#}", e),
+            }
+            next_refresh = now + interval;
+        }
+        let request = match server.recv_timeout(next_refresh.saturating_duration_since(now))? {
+            Some(request) => request,
+            None => continue,
+        };
+        if request.method() == &Method::Get && request.url() == "/metrics" {
+            let gates = snapshot.gates.as_ref();
+            let response = match metrics::render(
+                workdir,
+                &snapshot.configs,
+                gates,
+                ignore_queue,
+                &snapshot.credentials,
+                snapshot.known_hosts.clone(),
+            ) {
+                Result::Ok(body) => Response::from_string(body).with_header(metrics_type.clone()),
+                Err(e) => Response::from_string(format!("{:#}", e)).with_status_code(500),
+            };
+            if let Err(e) = request.respond(response) {
+                eprintln!("Couldn't send response: {}", e);
+            }
+            continue;
+        }
+        let (status, body) = if request.method() != &Method::Get {
+            (405, json!({ "error": "Only GET requests are supported" }))
+        } else {
+            match route(workdir, ignore_queue, &snapshot, request.url()) {
+                Result::Ok(Some(body)) => (200, body),
+                Result::Ok(None) => (404, json!({ "error": "Not found" })),
+                Err(e) => (status_of(&e), json!({ "error": format!("{:#}", e) })),
+            }
+        };
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(json_type.clone());
+        if let Err(e) = request.respond(response) {
+            eprintln!("Couldn't send response: {}", e);
+        }
+    }
+}
+
+fn route(
+    workdir: &Path,
+    ignore_queue: bool,
+    snapshot: &Snapshot,
+    url: &str,
+) -> Result<Option<Value>> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (deployment, env_name, rest) = match segments.as_slice() {
+        ["environments"] => return list_environments(workdir, ignore_queue, snapshot).map(Some),
+        ["environments", deployment, env_name, rest @ ..] => (*deployment, *env_name, rest),
+        _ => return Ok(None),
+    };
+    let (config, config_path) = match snapshot
+        .configs
+        .iter()
+        .find(|(config, _)| config.scope == deployment)
+    {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+    let env = match config.environments.get(env_name) {
+        Some(env) => env,
+        None => return Ok(None),
+    };
+    let mut ws = Workspace::new(workdir, &config.scope, config_path.clone(), ignore_queue)?;
+    ws.set_first_parent(config.first_parent);
+    ws.set_credentials(snapshot.credentials.clone(), snapshot.known_hosts.clone());
+    ws.set_event_sink(Arc::new(|_: &Event| ()));
+    let body = match rest {
+        [] => json!({
+            "deployment": config.scope,
+            "environment": env.name,
+            "config": config_path,
+            "passed": env.propagated_from(),
+            "lock": ws.get_lock(env).map(|lock| &lock.reason),
+            "state": serde_json::to_value(ws.get_current_state(env))?,
+        }),
+        ["diff"] => {
+            let gate = match snapshot.gates.as_ref() {
+                Some(gates) => gates.get_gate(&env.name)?,
+                None => None,
+            };
+            match ws.pending_diff(env, gate)? {
+                Some(report) => json!({
+                    "trigger": report.trigger,
+                    "files": report.diffs.iter().map(|diff| json!({
+                        "file": diff.ident.name(),
+                        "change": if diff.added {
+                            "added"
+                        } else if diff.current_state.is_some() {
+                            "changed"
+                        } else {
+                            "removed"
+                        },
+                    })).collect::<Vec<_>>(),
+                }),
+                None => json!({ "trigger": null, "files": [] }),
+            }
+        }
+        ["queue"] => serde_json::to_value(ws.get_propagation_queue(env))?,
+        ["history"] => {
+            let limit = match query
+                .split('&')
+                .find_map(|param| param.strip_prefix("limit="))
+            {
+                Some(limit) => limit
+                    .parse()
+                    .context(format!("Invalid limit '{}'", limit))?,
+                None => DEFAULT_HISTORY_LIMIT,
+            };
+            serde_json::to_value(ws.history(env, limit)?)?
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(body))
+}
+
+fn list_environments(workdir: &Path, ignore_queue: bool, snapshot: &Snapshot) -> Result<Value> {
+    let mut ret = Vec::new();
+    for (config, config_path) in snapshot.configs.iter() {
+        let ws = Workspace::new(workdir, &config.scope, config_path.clone(), ignore_queue)?;
+        let mut envs: Vec<_> = config.environments.values().collect();
+        envs.sort_by(|a, b| a.name.cmp(&b.name));
+        for env in envs {
+            ret.push(json!({
+                "deployment": config.scope,
+                "environment": env.name,
+                "config": config_path,
+                "passed": env.propagated_from(),
+                "lock": ws.get_lock(env).map(|lock| &lock.reason),
+                "trigger": ws.get_current_state(env).map(|state| &state.head_commit),
+            }));
+        }
+    }
+    Ok(Value::Array(ret))
+}
+
+fn status_of(err: &Error) -> i32 {
+    match err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<CeplerError>())
+    {
+        Some(CeplerError::UpstreamNotDeployed(_)) => 409,
+        Some(CeplerError::GateInvalid(_)) => 400,
+        _ => 500,
+    }
+}
@@ -0,0 +1,44 @@
+use super::config::SigningFormat;
+use anyhow::*;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Returns the armored signature of `content` made with `key`.
+/// Signing is delegated to `gpg` or `ssh-keygen` the same way git does it.
+pub fn sign(content: &str, key: &str, format: SigningFormat) -> Result<String> {
+    let mut command = match format {
+        SigningFormat::Openpgp => {
+            let mut command = Command::new("gpg");
+            command.args(["--status-fd=2", "-bsau", key]);
+            command
+        }
+        SigningFormat::Ssh => {
+            let mut command = Command::new("ssh-keygen");
+            command.args(["-Y", "sign", "-n", "git", "-f", key]);
+            command
+        }
+    };
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("Couldn't run '{}' to sign the commit", program))?;
+    child
+        .stdin
+        .take()
+        .context("Couldn't open stdin of signing program")?
+        .write_all(content.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Couldn't sign commit with '{}': {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).context("Signature is not valid utf-8")
+}
@@ -1,47 +1,263 @@
-use super::{config::*, database::*, repo::*};
+use super::{config::*, database::*, error::*, events::*, lfs, repo::*};
 use anyhow::*;
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// An environment that needs deploying.
+#[derive(Debug)]
+pub struct CheckReport {
+    /// The commit that triggered the new state.
+    pub trigger: String,
+    pub diffs: Vec<FileDiff>,
+}
+
+/// The outcome of recording an environment.
+#[derive(Debug)]
+pub struct RecordReport {
+    /// The commit the recorded state was triggered by.
+    pub trigger: String,
+    /// Path of the state file relative to the repository root.
+    pub state_file: String,
+    pub diffs: Vec<FileDiff>,
+}
 
 pub struct Workspace {
+    workdir: PathBuf,
     path_to_config: String,
     scope: String,
     ignore_queue: bool,
+    upstream_state: Option<DeployState>,
+    ignore_lock: bool,
+    events: Arc<dyn EventSink>,
+    commit_config: CommitConfig,
+    first_parent: bool,
+    credentials: GitCredentials,
+    known_hosts: Option<String>,
     db: Database,
 }
 
 impl Workspace {
-    pub fn new(scope: &str, path_to_config: String, ignore_queue: bool) -> Result<Self> {
+    pub fn new(
+        repo_dir: &Path,
+        scope: &str,
+        path_to_config: String,
+        ignore_queue: bool,
+    ) -> Result<Self> {
+        let workdir = Repo::open(repo_dir, None)?.workdir()?;
         Ok(Self {
-            db: Database::open(scope, &path_to_config, ignore_queue)?,
+            db: Database::open(&workdir, scope, &path_to_config, ignore_queue)?,
+            workdir,
             scope: scope.to_string(),
             path_to_config,
             ignore_queue,
+            upstream_state: None,
+            ignore_lock: false,
+            events: Arc::new(StderrSink),
+            commit_config: CommitConfig::default(),
+            first_parent: false,
+            credentials: GitCredentials::default(),
+            known_hosts: None,
         })
     }
 
+    pub fn set_event_sink(&mut self, events: Arc<dyn EventSink>) {
+        self.events = events;
+    }
+
+    /// Sets the author and signing key of state and lock commits.
+    /// `CEPLER_AUTHOR_NAME`, `CEPLER_AUTHOR_EMAIL`, `CEPLER_SIGNING_KEY` and
+    /// `CEPLER_SIGNING_FORMAT` take precedence over `config`.
+    pub fn set_commit_config(&mut self, config: CommitConfig) -> Result<()> {
+        self.commit_config = config.with_env()?;
+        Ok(())
+    }
+
+    /// Only follow the first parent of merge commits when looking for triggers
+    /// and the commits that last changed files.
+    pub fn set_first_parent(&mut self, first_parent: bool) {
+        self.first_parent = first_parent;
+    }
+
+    /// Sets the credentials and known_hosts used to fetch submodules
+    /// and the history and objects missing from shallow and partial clones.
+    pub fn set_credentials(&mut self, credentials: GitCredentials, known_hosts: Option<String>) {
+        self.credentials = credentials;
+        self.known_hosts = known_hosts;
+    }
+
+    /// Allow preparing and recording environments that are locked.
+    pub fn ignore_lock(&mut self) {
+        self.ignore_lock = true;
+    }
+
+    pub fn lock(&mut self, env: &EnvironmentConfig, reason: String, commit: bool) -> Result<()> {
+        let lock_file = self
+            .db
+            .set_lock(env.name.clone(), Some(EnvironmentLock { reason }))?;
+        if commit {
+            self.events.event(&Event::CommittingLock);
+            self.open_repo(None)?
+                .commit_lock_file(&self.scope, lock_file)?;
+        }
+        Ok(())
+    }
+
+    pub fn unlock(&mut self, env: &EnvironmentConfig, commit: bool) -> Result<()> {
+        if self.db.get_lock(&env.name).is_none() {
+            return Err(anyhow!("Environment '{}' is not locked", env.name));
+        }
+        let lock_file = self.db.set_lock(env.name.clone(), None)?;
+        if commit {
+            self.events.event(&Event::RemovingLock);
+            self.open_repo(None)?
+                .commit_lock_file(&self.scope, lock_file)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_lock(&self, env: &EnvironmentConfig) -> Option<&EnvironmentLock> {
+        self.db.get_lock(&env.name)
+    }
+
+    pub fn get_current_state(&self, env: &EnvironmentConfig) -> Option<&DeployState> {
+        self.db.get_current_state(&env.name)
+    }
+
+    /// Commit time of the last committed record of `env`.
+    pub fn last_recorded_at(&self, repo: &Repo, env: &EnvironmentConfig) -> Result<Option<i64>> {
+        let state_file = format!("{}/{}.state", self.db.state_dir, env.name);
+        repo.last_change_time(Path::new(&state_file))
+    }
+
+    /// Returns each distinct committed state of `env` together with the commit that recorded it.
+    pub fn recorded_states(
+        &self,
+        repo: &Repo,
+        env: &EnvironmentConfig,
+    ) -> Result<Vec<(CommitHash, DeployState)>> {
+        self.db.recorded_states(repo, &env.name)
+    }
+
+    pub fn get_propagation_queue(&self, env: &EnvironmentConfig) -> Vec<&DeployState> {
+        self.db.get_propagation_queue(&env.name)
+    }
+
+    /// Returns up to `limit` states deployed to `env`, starting with the current one.
+    pub fn history(&self, env: &EnvironmentConfig, limit: usize) -> Result<Vec<DeployState>> {
+        let repo = self.open_repo(None)?;
+        self.db.deploy_history(&repo, &env.name, limit)
+    }
+
+    fn ensure_unlocked(&self, env: &EnvironmentConfig) -> Result<()> {
+        match self.db.get_lock(&env.name) {
+            Some(lock) if !self.ignore_lock => Err(CeplerError::EnvironmentLocked {
+                environment: env.name.clone(),
+                reason: lock.reason.clone(),
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Propagate the upstream state recorded with `trigger` instead of the one
+    /// chosen via the propagation queue.
+    pub fn target_upstream_state(&mut self, env: &EnvironmentConfig, trigger: &str) -> Result<()> {
+        let previous_env = env.propagated_from().context(format!(
+            "Environment '{}' is not propagated from another environment",
+            env.name
+        ))?;
+        let repo = self.open_repo(None)?;
+        let state = self
+            .db
+            .find_propagated_state(&repo, previous_env, trigger)?
+            .context(format!(
+                "State '{}' not found in queue or history of '{}'",
+                trigger, previous_env
+            ))?;
+        if let Some(propagated_head) = self
+            .db
+            .get_current_state(&env.name)
+            .and_then(|state| state.propagated_head.as_ref())
+        {
+            if !repo.is_ancestor(propagated_head, &state.head_commit)? {
+                return Err(anyhow!(
+                    "State '{}' of '{}' is older than '{}' already propagated to '{}'",
+                    state.head_commit,
+                    previous_env,
+                    propagated_head,
+                    env.name
+                ));
+            }
+        }
+        self.upstream_state = Some(state);
+        Ok(())
+    }
+
+    fn target_propagated_state<'a>(
+        &'a self,
+        database: &'a Database,
+        env: &EnvironmentConfig,
+        previous_env: &str,
+        patterns: &[glob::Pattern],
+    ) -> Option<&'a DeployState> {
+        if let Some(state) = self.upstream_state.as_ref() {
+            return Some(state);
+        }
+        database.get_target_propagated_state(&env.name, env.ignore_queue, previous_env, patterns)
+    }
+
     pub fn ls(&self, env: &EnvironmentConfig, gate: Option<String>) -> Result<Vec<String>> {
-        let repo = Repo::open(gate)?;
+        let repo = self.open_repo(gate)?;
         let new_env_state = self.construct_env_state(&repo, env, false)?;
-        Ok(new_env_state
-            .files
-            .into_iter()
-            .map(|(k, _)| k.name())
-            .collect())
+        Ok(new_env_state.files.into_keys().map(|k| k.name()).collect())
     }
 
     pub fn check(
         &self,
         env: &EnvironmentConfig,
         gate: Option<String>,
-    ) -> Result<Option<(String, Vec<FileDiff>)>> {
-        let repo = Repo::open(gate)?;
+    ) -> Result<Option<CheckReport>> {
+        let repo = self.open_repo(gate)?;
+        self.check_in(&repo, env)
+    }
+
+    /// Like `check` but reuses `repo` so that checks of several environments
+    /// don't reopen it (or deepen a shallow clone again).
+    pub fn check_in(&self, repo: &Repo, env: &EnvironmentConfig) -> Result<Option<CheckReport>> {
+        if let Some(lock) = self.db.get_lock(&env.name) {
+            self.events.event(&Event::EnvironmentLocked {
+                environment: env.name.clone(),
+                reason: lock.reason.clone(),
+            });
+            return Ok(None);
+        }
+        self.pending_diff_in(repo, env)
+    }
+
+    /// The files that would change on the next deploy of `env`, even while it is locked.
+    pub fn pending_diff(
+        &self,
+        env: &EnvironmentConfig,
+        gate: Option<String>,
+    ) -> Result<Option<CheckReport>> {
+        let repo = self.open_repo(gate)?;
+        self.pending_diff_in(&repo, env)
+    }
+
+    /// Like `pending_diff` but reuses `repo`.
+    pub fn pending_diff_in(
+        &self,
+        repo: &Repo,
+        env: &EnvironmentConfig,
+    ) -> Result<Option<CheckReport>> {
         if let Some(previous_env) = env.propagated_from() {
-            self.db.get_current_state(previous_env).context(format!(
-                "Previous environment '{}' not deployed yet",
-                previous_env
-            ))?;
+            if self.db.get_current_state(previous_env).is_none() {
+                return Err(CeplerError::UpstreamNotDeployed(previous_env.clone()).into());
+            }
         }
-        let new_env_state = self.construct_env_state(&repo, env, false)?;
+        let new_env_state = self.construct_env_state(repo, env, false)?;
         let diffs = if let Some(last) = self.db.get_current_state(&env.name) {
             let diffs = new_env_state.diff(last);
             if diffs.is_empty() {
@@ -61,44 +277,155 @@ impl Workspace {
         };
         for diff in diffs.iter() {
             let name = diff.ident.name();
-            if diff.added {
-                eprintln!("File {} was added", name)
+            self.events.event(&if diff.added {
+                Event::FileAdded(name)
             } else if diff.current_state.is_some() {
-                eprintln!("File {} changed", name)
+                Event::FileChanged(name)
             } else {
-                eprintln!("File {} was removed", name)
-            }
+                Event::FileRemoved(name)
+            });
         }
-        Ok(Some((new_env_state.head_commit.inner(), diffs)))
+        Ok(Some(CheckReport {
+            trigger: new_env_state.head_commit.inner(),
+            diffs,
+        }))
     }
 
-    pub fn reproduce(&self, env: &EnvironmentConfig, force_clean: bool) -> Result<()> {
-        let repo = Repo::open(None)?;
-        if let Some(last_state) = self.db.get_current_state(&env.name) {
-            if force_clean {
-                repo.checkout_gate(&[], &self.ignore_list(), true)?;
+    pub fn diff_content(
+        &self,
+        env: &EnvironmentConfig,
+        gate: Option<String>,
+    ) -> Result<Vec<String>> {
+        let diffs = if let Some(report) = self.pending_diff(env, gate.clone())? {
+            report.diffs
+        } else {
+            return Ok(Vec::new());
+        };
+        let repo = self.open_repo(gate)?;
+        let last_state = self.db.get_current_state(&env.name);
+        let mut ret = Vec::new();
+        for diff in diffs {
+            let name = diff.ident.name();
+            let old = last_state.and_then(|state| state.files.get(&diff.ident));
+            let new = diff.current_state.as_ref();
+            if let Some(old) = old.filter(|state| state.dirty) {
+                // Only the hash of dirty content is recorded so the blob is the closest we have
+                ret.push(format!(
+                    "Note: '{}' was recorded dirty, the deployed content differs from [{}]\n",
+                    name,
+                    old.from_commit.to_short_ref()
+                ));
             }
-            for (ident, state) in last_state.files.iter() {
-                repo.checkout_file_from(&ident.name(), &state.from_commit)?;
+            let lfs_oid = |state: Option<&FileState>| {
+                state
+                    .and_then(|state| state.lfs_oid.clone())
+                    .unwrap_or_else(|| "none".to_string())
+            };
+            if [old, new]
+                .iter()
+                .flatten()
+                .any(|state| state.lfs_oid.is_some())
+            {
+                // Diffing the pointer files would only show the oids
+                ret.push(format!(
+                    "LFS object {}: oid {} \u{2192} {}\n",
+                    name,
+                    lfs_oid(old),
+                    lfs_oid(new)
+                ));
+                continue;
             }
-            Ok(())
-        } else {
-            Err(anyhow!("No state recorded for {}", env.name))
+            ret.push(repo.diff_file_content(
+                Path::new(&name),
+                old.map(|state| &state.from_commit),
+                new.map(|state| &state.from_commit),
+            )?);
         }
+        Ok(ret)
+    }
+
+    /// Restores the files of a recorded state of `env`.
+    /// Defaults to the current state, `at` selects an earlier one (see [`Database::find_deployed_state`]).
+    /// If `output` is given the files are written there instead of the working directory.
+    pub fn reproduce(
+        &self,
+        env: &EnvironmentConfig,
+        force_clean: bool,
+        at: Option<&str>,
+        output: Option<&Path>,
+    ) -> Result<()> {
+        let repo = self.open_repo(None)?;
+        let state = match at {
+            Some(at) => self
+                .db
+                .find_deployed_state(&repo, &env.name, at)?
+                .with_context(|| {
+                    format!("No state recorded for '{}' matches '{}'", env.name, at)
+                })?,
+            None => self
+                .db
+                .get_current_state(&env.name)
+                .cloned()
+                .ok_or_else(|| CeplerError::NotDeployed(env.name.clone()))?,
+        };
+        if let Some(output) = output {
+            for (ident, file_state) in state.files.iter() {
+                let name = ident.name();
+                let bytes = repo
+                    .get_file_content(file_state.from_commit.clone(), Path::new(&name), |bytes| {
+                        Ok(bytes.to_vec())
+                    })?
+                    .with_context(|| {
+                        format!(
+                            "File '{}' not found in commit '{}'",
+                            name,
+                            file_state.from_commit.clone().inner()
+                        )
+                    })?;
+                let bytes = repo.smudge(bytes)?;
+                let target = output.join(&name);
+                if file_state.mode == Some(FileMode::Submodule) {
+                    self.events.event(&Event::SubmoduleNotCheckedOut {
+                        path: name.clone(),
+                        commit: file_state
+                            .file_hash
+                            .clone()
+                            .map(|hash| hash.inner())
+                            .unwrap_or_default(),
+                    });
+                }
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                write_file(&target, &bytes, file_state.mode.unwrap_or_default())
+                    .context(format!("Couldn't write '{}'", target.display()))?;
+            }
+            return Ok(());
+        }
+        if force_clean {
+            repo.checkout_gate(&[], &self.ignore_list()?, true)?;
+        }
+        for (ident, file_state) in state.files.iter() {
+            repo.checkout_file_from(&ident.name(), &file_state.from_commit)?;
+        }
+        Ok(())
     }
     pub fn prepare(
         &self,
         env: &EnvironmentConfig,
         gate: Option<String>,
         force_clean: bool,
+        only: &[glob::Pattern],
     ) -> Result<()> {
-        let repo = Repo::open(gate)?;
-        let ignore_list = self.ignore_list();
-        let head_patterns: Vec<_> = env.head_file_patterns().collect();
+        self.ensure_unlocked(env)?;
+        let repo = self.open_repo(gate)?;
+        let ignore_list = self.ignore_list()?;
+        let head_patterns = env.head_file_patterns()?;
         repo.checkout_gate(&head_patterns, &ignore_list, force_clean)?;
-        for file_buf in env.propagated_files() {
+        for file_buf in env.propagated_files(&self.workdir)? {
             let file = file_buf.as_path();
-            if file.is_file()
+            let full_path = self.workdir.join(file);
+            if full_path.is_file()
                 && !ignore_list
                     .iter()
                     .any(|p| p.matches_path_with(file, MATCH_OPTIONS))
@@ -106,17 +433,15 @@ impl Workspace {
                     .iter()
                     .any(|p| p.matches_path_with(file, MATCH_OPTIONS))
             {
-                std::fs::remove_file(file_buf).expect("Couldn't remove file");
+                std::fs::remove_file(&full_path)
+                    .context(format!("Couldn't remove file '{}'", full_path.display()))?;
             }
         }
         if let Some(previous_env) = env.propagated_from() {
-            let patterns: Vec<_> = env.propagated_file_patterns().collect();
-            if let Some(env_state) = self.db.get_target_propagated_state(
-                &env.name,
-                env.ignore_queue,
-                previous_env,
-                &patterns,
-            ) {
+            let patterns = env.propagated_file_patterns()?;
+            if let Some(env_state) =
+                self.target_propagated_state(&self.db, env, previous_env, &patterns)
+            {
                 for (ident, state) in env_state.files.iter() {
                     let name = ident.name();
                     if patterns
@@ -131,6 +456,49 @@ impl Workspace {
                 }
             }
         }
+        if !only.is_empty() {
+            self.restore_excluded_files(&repo, env, only)?;
+        }
+        Ok(())
+    }
+
+    fn restore_excluded_files(
+        &self,
+        repo: &Repo,
+        env: &EnvironmentConfig,
+        only: &[glob::Pattern],
+    ) -> Result<()> {
+        let ignore_list = self.ignore_list()?;
+        let excluded = |file: &Path| {
+            !only
+                .iter()
+                .any(|p| p.matches_path_with(file, MATCH_OPTIONS))
+        };
+        for file_buf in env
+            .head_files(&self.workdir)?
+            .into_iter()
+            .chain(env.propagated_files(&self.workdir)?)
+        {
+            let file = file_buf.as_path();
+            let full_path = self.workdir.join(file);
+            if full_path.is_file()
+                && excluded(file)
+                && !ignore_list
+                    .iter()
+                    .any(|p| p.matches_path_with(file, MATCH_OPTIONS))
+            {
+                std::fs::remove_file(&full_path)
+                    .context(format!("Couldn't remove file '{}'", full_path.display()))?;
+            }
+        }
+        if let Some(last_state) = self.db.get_current_state(&env.name) {
+            for (ident, state) in last_state.files.iter() {
+                let name = ident.name();
+                if excluded(Path::new(&name)) {
+                    repo.checkout_file_from(&name, &state.from_commit)?;
+                }
+            }
+        }
         Ok(())
     }
 
@@ -141,10 +509,13 @@ impl Workspace {
         commit: bool,
         reset: bool,
         git_config: Option<GitConfig>,
-    ) -> Result<(String, Vec<FileDiff>)> {
-        eprintln!("Recording current state");
-        let repo = Repo::open(gate)?;
-        let new_env_state = self.construct_env_state(&repo, env, true)?;
+        only: &[glob::Pattern],
+    ) -> Result<RecordReport> {
+        self.ensure_unlocked(env)?;
+        self.events.event(&Event::RecordingState);
+        let repo = self.open_repo(gate)?;
+        let mut new_env_state = self.construct_env_state(&repo, env, true)?;
+        new_env_state.restrict_to(only, self.db.get_current_state(&env.name));
         let head_commit = new_env_state.head_commit.clone().inner();
         let diffs = if let Some(last_state) = self.db.get_current_state(&env.name) {
             new_env_state.diff(last_state)
@@ -165,18 +536,61 @@ impl Workspace {
             new_env_state,
         )?;
         if commit {
-            eprintln!("Adding commit to repository to persist state");
-            repo.commit_state_file(&self.scope, state_file)?;
+            self.events.event(&Event::CommittingState);
+            let message = self.state_message(env, &head_commit, &diffs)?;
+            repo.commit_state_file(&self.scope, state_file.clone(), message)?;
         }
         if reset {
-            eprintln!("Reseting head to have a clean workspace");
+            self.events.event(&Event::ResettingHead);
             repo.checkout_head()?;
         }
         if let Some(config) = git_config {
-            eprintln!("Pushing to remote");
+            self.events.event(&Event::Pushing);
             repo.push(config)?;
         }
-        Ok((head_commit, diffs))
+        Ok(RecordReport {
+            trigger: head_commit,
+            state_file,
+            diffs,
+        })
+    }
+
+    /// Renders the configured commit message template for recording `diffs`.
+    fn state_message(
+        &self,
+        env: &EnvironmentConfig,
+        trigger: &str,
+        diffs: &[FileDiff],
+    ) -> Result<Option<String>> {
+        let changes: Vec<_> = diffs
+            .iter()
+            .map(|diff| match diff.current_state.as_ref() {
+                Some(state) => format!(
+                    "- {} ({} {})",
+                    diff.ident.name(),
+                    state.from_commit.to_short_ref(),
+                    state.message
+                ),
+                None => format!("- {} (removed)", diff.ident.name()),
+            })
+            .collect();
+        let trigger_short: String = trigger.chars().take(7).collect();
+        self.commit_config.state_message(&[
+            ("deployment", &self.scope),
+            ("environment", &env.name),
+            ("trigger", trigger),
+            ("trigger_short", &trigger_short),
+            ("changed_count", &diffs.len().to_string()),
+            ("changes", &changes.join("\n")),
+        ])
+    }
+
+    fn open_repo(&self, gate: Option<String>) -> Result<Repo> {
+        let mut repo = Repo::open(&self.workdir, gate)?;
+        repo.set_commit_config(self.commit_config.clone());
+        repo.set_first_parent(self.first_parent);
+        repo.set_credentials(self.credentials.clone(), self.known_hosts.clone());
+        Ok(repo)
     }
 
     #[allow(clippy::redundant_closure)]
@@ -186,7 +600,7 @@ impl Workspace {
         env: &EnvironmentConfig,
         recording: bool,
     ) -> Result<DeployState> {
-        let current_commit = repo.gate_commit_hash();
+        let current_commit = repo.gate_commit_hash()?;
         let database = self.db.open_env_from_commit(
             &self.path_to_config,
             self.ignore_queue,
@@ -226,7 +640,7 @@ impl Workspace {
     ) -> Result<Option<DeployState>> {
         let config = if let Some(config) =
             repo.get_file_content(commit.clone(), Path::new(&self.path_to_config), |bytes| {
-                Config::from_reader(bytes)
+                Ok(Config::from_reader(bytes)?.with_path_to_config(&self.path_to_config))
             })? {
             config
         } else {
@@ -263,13 +677,10 @@ impl Workspace {
     ) -> Result<DeployState> {
         let mut new_env_state = DeployState::new(commit.clone());
         if let Some(previous_env) = env.propagated_from() {
-            let patterns: Vec<_> = env.propagated_file_patterns().collect();
-            if let Some(env_state) = database.get_target_propagated_state(
-                &env.name,
-                env.ignore_queue,
-                previous_env,
-                &patterns,
-            ) {
+            let patterns = env.propagated_file_patterns()?;
+            if let Some(env_state) =
+                self.target_propagated_state(database, env, previous_env, &patterns)
+            {
                 new_env_state.propagated_head = Some(env_state.head_commit.clone());
                 for (ident, prev_state) in env_state.files.iter() {
                     let name = ident.name();
@@ -278,18 +689,21 @@ impl Workspace {
                             .iter()
                             .any(|p| p.matches_with(&name, MATCH_OPTIONS))
                         {
-                            let (dirty, file_hash) = if recording {
-                                if let Some(file_hash) = hash_file(&name) {
-                                    (&file_hash != last_hash, Some(file_hash))
-                                } else {
-                                    (true, None)
-                                }
+                            let (dirty, file_hash, mode) = if recording {
+                                self.compare_on_disk(
+                                    Path::new(&name),
+                                    last_hash,
+                                    prev_state.mode,
+                                    prev_state.lfs_oid.as_deref(),
+                                )?
                             } else {
-                                (false, Some(last_hash.clone()))
+                                (false, Some(last_hash.clone()), prev_state.mode)
                             };
                             let file_state = FileState {
                                 dirty,
                                 file_hash,
+                                mode,
+                                lfs_oid: prev_state.lfs_oid.clone(),
                                 from_commit: prev_state.from_commit.clone(),
                                 message: prev_state.message.clone(),
                             };
@@ -302,13 +716,14 @@ impl Workspace {
                 }
             }
         }
-        let ignore_list = vec![
-            glob::Pattern::new(&self.path_to_config).unwrap(),
-            glob::Pattern::new(&format!("{}/*", database.state_dir)).unwrap(),
+        let ignore_list = [
+            literal_pattern(&self.path_to_config)?,
+            state_dir_pattern(&database.state_dir)?,
         ];
-        repo.all_files(commit.clone(), |file_hash, path| {
-            if env
-                .head_file_patterns()
+        let head_patterns = env.head_file_patterns()?;
+        repo.all_files(commit.clone(), |file_hash, mode, path| {
+            if head_patterns
+                .iter()
                 .any(|p| p.matches_path_with(path, MATCH_OPTIONS))
                 && !ignore_list
                     .iter()
@@ -316,30 +731,31 @@ impl Workspace {
             {
                 let (from_commit, message) = repo.find_last_changed_commit(path, commit.clone())?;
                 let state = if recording {
-                    if let Some(on_disk_hash) = hash_file(path) {
-                        FileState {
-                            dirty: file_hash != on_disk_hash,
-                            file_hash: Some(on_disk_hash),
-                            from_commit,
-                            message,
-                        }
-                    } else {
-                        FileState {
-                            dirty: true,
-                            file_hash: None,
-                            from_commit,
-                            message,
-                        }
+                    let lfs_oid = repo.lfs_pointer(&file_hash)?.map(|pointer| pointer.oid);
+                    let (dirty, file_hash, mode) =
+                        self.compare_on_disk(path, &file_hash, Some(mode), lfs_oid.as_deref())?;
+                    FileState {
+                        dirty,
+                        file_hash,
+                        mode,
+                        lfs_oid,
+                        from_commit,
+                        message,
                     }
                 } else {
                     FileState {
                         dirty: false,
                         file_hash: Some(file_hash),
+                        mode: Some(mode),
+                        lfs_oid: None,
                         from_commit,
                         message,
                     }
                 };
-                let file_name = path.to_str().unwrap().to_string();
+                let file_name = path
+                    .to_str()
+                    .context(format!("Path '{}' is not valid utf-8", path.display()))?
+                    .to_string();
                 new_env_state
                     .files
                     .insert(FileIdent::new(file_name, None), state);
@@ -349,12 +765,98 @@ impl Workspace {
         Ok(new_env_state)
     }
 
-    fn ignore_list(&self) -> Vec<glob::Pattern> {
-        vec![
-            glob::Pattern::new(&self.path_to_config).unwrap(),
-            glob::Pattern::new(&format!("{}/*", self.db.state_dir)).unwrap(),
-            glob::Pattern::new(".git/*").unwrap(),
-            glob::Pattern::new(".gitignore").unwrap(),
-        ]
+    /// Compares the file at `path` with the expected `hash` and `mode`.
+    /// Files tracked via LFS are compared by their LFS object id.
+    /// Returns whether the file is dirty along with the hash and mode found on disk.
+    fn compare_on_disk(
+        &self,
+        path: &Path,
+        hash: &FileHash,
+        mode: Option<FileMode>,
+        lfs_oid: Option<&str>,
+    ) -> Result<(bool, Option<FileHash>, Option<FileMode>)> {
+        let full_path = self.workdir.join(path);
+        let on_disk = if mode == Some(FileMode::Submodule) {
+            (hash_submodule(&full_path)?, mode)
+        } else {
+            (hash_file(&full_path)?, file_mode(&full_path)?)
+        };
+        let (on_disk_hash, on_disk_mode) = match on_disk {
+            (Some(on_disk_hash), Some(on_disk_mode)) => (on_disk_hash, on_disk_mode),
+            _ => return Ok((true, None, mode)),
+        };
+        if let Some(lfs_oid) = lfs_oid {
+            if !mode_changed(Some(on_disk_mode), mode) && lfs::file_oid(&full_path)? == lfs_oid {
+                return Ok((false, Some(hash.clone()), Some(on_disk_mode)));
+            }
+        }
+        Ok((
+            &on_disk_hash != hash || mode_changed(Some(on_disk_mode), mode),
+            Some(on_disk_hash),
+            Some(on_disk_mode),
+        ))
+    }
+
+    fn ignore_list(&self) -> Result<Vec<glob::Pattern>> {
+        Ok(vec![
+            literal_pattern(&self.path_to_config)?,
+            state_dir_pattern(&self.db.state_dir)?,
+            state_dir_pattern(".git")?,
+            literal_pattern(".gitignore")?,
+        ])
+    }
+}
+
+#[cfg(unix)]
+fn write_file(path: &Path, bytes: &[u8], mode: FileMode) -> Result<()> {
+    use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+    // Don't write through a symlink or keep the permissions of what was there before
+    remove_existing(path)?;
+    match mode {
+        FileMode::Symlink => {
+            std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(bytes), path)?;
+        }
+        FileMode::Executable => {
+            std::fs::write(path, bytes)?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+        }
+        FileMode::Regular => {
+            std::fs::write(path, bytes)?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644))?;
+        }
+        // Like an uninitialized submodule in a fresh clone
+        FileMode::Submodule => std::fs::create_dir_all(path)?,
+    }
+    Ok(())
+}
+
+/// Removes whatever is at `path` so it can be written from scratch.
+#[cfg(unix)]
+fn remove_existing(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Result::Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path)?,
+        Result::Ok(_) => std::fs::remove_file(path)?,
+        Err(_) => (),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_file(path: &Path, bytes: &[u8], mode: FileMode) -> Result<()> {
+    if mode == FileMode::Submodule {
+        std::fs::create_dir_all(path)?;
+    } else {
+        std::fs::write(path, bytes)?;
     }
+    Ok(())
+}
+
+fn literal_pattern(path: &str) -> Result<glob::Pattern> {
+    glob::Pattern::new(&glob::Pattern::escape(path))
+        .context(format!("Couldn't build pattern for '{}'", path))
+}
+
+fn state_dir_pattern(state_dir: &str) -> Result<glob::Pattern> {
+    glob::Pattern::new(&format!("{}/*", glob::Pattern::escape(state_dir)))
+        .context(format!("Couldn't build pattern for '{}'", state_dir))
 }
@@ -0,0 +1,66 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'check_all'"
+  prepare_test "check_all"
+}
+
+teardown_file() {
+  echo "Tearing down 'check_all'"
+  reset_repo_state
+}
+
+cepler() {
+  $(cepler_bin) $@
+}
+
+@test "Check all environments of multiple configs" {
+  output=$(cepler -c $(fixture)/cepler.yml -c $(fixture)/cepler-infra.yml check --all)
+  echo "${output}" | grep "apps/testflight - trigger commit"
+  echo "${output}" | grep "infra/testflight - trigger commit"
+  [ "$(echo "${output}" | grep -c staging)" == "0" ]
+
+  cepler -c $(fixture)/cepler-infra.yml record -e testflight
+  output=$(cepler -c $(fixture)/cepler.yml -c $(fixture)/cepler-infra.yml check --all)
+  echo "${output}" | grep "infra/staging - trigger commit"
+  [ "$(echo "${output}" | grep -c infra/testflight)" == "0" ]
+}
+
+@test "Discover configs below the current directory" {
+  cepler -c $(fixture)/cepler.yml record -e testflight
+  cepler -c $(fixture)/cepler-infra.yml record -e staging
+
+  cd $(fixture)
+  run cepler check --all
+  [ "$status" -eq 2 ]
+
+  echo "field: changed" > infra/network.yml
+  git commit -am 'Update network.yml'
+  output=$(cepler check --all)
+  echo "${output}" | grep "infra/testflight - trigger commit"
+  echo "${output}" | grep "cepler-infra.yml"
+  [ "$(echo "${output}" | grep -c apps/testflight)" == "0" ]
+}
+
+@test "Environments missing from the gates file are reported and skipped" {
+  echo "testflight: HEAD" > $(fixture)/gates.yml
+  run cepler -c $(fixture)/cepler.yml -c $(fixture)/cepler-infra.yml -g $(fixture)/gates.yml check --all
+  echo "${output}" | grep "Skipping infra/staging: .*'staging' is missing in gates file"
+  rm $(fixture)/gates.yml
+}
+
+@test "Only multi-config commands accept more than one config" {
+  run cepler -c $(fixture)/cepler.yml -c $(fixture)/cepler-infra.yml check -e testflight
+  [ "$status" -eq 1 ]
+  echo "${output}" | grep "accept more than one config"
+}
+
+@test "Configs must not share a deployment" {
+  cp $(fixture)/cepler.yml $(fixture)/cepler-copy.yml
+  run cepler -c $(fixture)/cepler.yml -c $(fixture)/cepler-copy.yml check --all
+  rm $(fixture)/cepler-copy.yml
+  [ "$status" -eq 10 ]
+  echo "${output}" | grep "both use the deployment 'apps'"
+}
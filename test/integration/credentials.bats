@@ -0,0 +1,28 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'credentials'"
+  prepare_test "credentials"
+}
+
+teardown_file() {
+  echo "Tearing down 'credentials'"
+  reset_repo_state
+}
+
+@test "Clones and pushes to a file:// remote without credentials" {
+  remote=${BATS_TMPDIR}/credentials_remote.git
+  clone=${BATS_TMPDIR}/credentials_clone
+  rm -rf ${remote} ${clone}
+  git clone --bare ${REPO_ROOT} ${remote}
+  git --git-dir=${remote} symbolic-ref HEAD refs/heads/credentials
+
+  $(cepler_bin) --clone ${clone} --git-url file://${remote} --git-branch credentials -c $(config) ls -e testflight \
+    | grep "test/fixtures/credentials/file.yml"
+
+  cd ${clone}
+  $(cepler_bin) -c $(config) record -e testflight --reset-head --push --git-url file://${remote} --git-branch credentials
+  [ "$(git --git-dir=${remote} rev-parse credentials)" == "$(git rev-parse HEAD)" ]
+}
@@ -0,0 +1,41 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'diff'"
+  prepare_test "diff"
+}
+
+teardown_file() {
+  echo "Tearing down 'diff'"
+  reset_repo_state
+}
+
+@test "Lists pending files" {
+  cmd record -e testflight
+  cmd diff -e staging | grep `fixture`/file.yml
+}
+
+@test "Shows content of pending changes" {
+  cmd record -e staging
+  echo "file_new: {}" > `fixture`/file.yml
+  git commit -am 'Update file.yml'
+  cmd record -e testflight
+
+  cmd diff -e staging --content | grep -- '-file: {}'
+  cmd diff -e staging --content | grep -- '+file_new: {}'
+}
+
+@test "Notes pending changes of files recorded dirty" {
+  echo "file_dirty: {}" > `fixture`/file.yml
+  cmd record -e staging
+  grep dirty `state staging`
+  git checkout `fixture`/file.yml
+  echo "file_newer: {}" > `fixture`/file.yml
+  git commit -am 'Update file.yml again'
+  cmd record -e testflight
+
+  cmd diff -e staging --content | grep "Note: '`fixture`/file.yml' was recorded dirty"
+  cmd diff -e staging --content | grep -- '+file_newer: {}'
+}
@@ -0,0 +1,45 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'errors'"
+  prepare_test "errors"
+}
+
+teardown_file() {
+  echo "Tearing down 'errors'"
+  rm -rf ${BATS_TMPDIR}/empty_repo
+  reset_repo_state
+}
+
+@test "Corrupt state file is reported" {
+  mkdir -p $(fixture)/.cepler/default
+  echo "current: [" > $(state "testflight")
+
+  run cmd check -e testflight
+  [ "$status" -eq 30 ]
+  echo "$output" | grep "testflight.state"
+
+  rm $(state "testflight")
+}
+
+@test "State referencing a missing commit is reported" {
+  cmd record -e testflight
+  sed -i "s/from_commit: .*/from_commit: 0000000000000000000000000000000000000000/" $(state "testflight")
+
+  run cmd prepare -e staging
+  [ "$status" -eq 1 ]
+  echo "$output" | grep "not found"
+}
+
+@test "Empty repository is reported" {
+  mkdir -p ${BATS_TMPDIR}/empty_repo
+  cp $(config) ${BATS_TMPDIR}/empty_repo/cepler.yml
+  cd ${BATS_TMPDIR}/empty_repo
+  git init
+
+  run $(cepler_bin) check -e testflight
+  [ "$status" -eq 1 ]
+  echo "$output" | grep "HEAD"
+}
@@ -0,0 +1,53 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'file_mode'"
+  prepare_test "file_mode"
+}
+
+teardown_file() {
+  echo "Tearing down 'file_mode'"
+  rm -rf ${BATS_TMPDIR}/file_mode_out
+  reset_repo_state
+}
+
+@test "Changing the mode of a file needs deploying" {
+  cmd record -e testflight
+  run cmd check -e testflight
+  [ "$status" -eq 2 ]
+
+  chmod +x $(fixture)/script.sh
+  git commit -am 'Make script executable'
+  cmd check -e testflight
+  cmd record -e testflight
+  grep "mode: executable" $(state "testflight")
+  run grep "dirty" $(state "testflight")
+  [ "$status" -ne 0 ]
+}
+
+@test "Prepare restores the mode of propagated files" {
+  chmod -x $(fixture)/script.sh
+  cmd prepare -e staging
+  [ -x $(fixture)/script.sh ]
+  cmd record -e staging
+  grep "mode: executable" $(state "staging")
+}
+
+@test "Symlinks are recorded by their target" {
+  ln -s script.sh $(fixture)/link.sh
+  git add $(fixture)/link.sh
+  git commit -m 'Add link'
+  cmd record -e testflight
+  grep "mode: symlink" $(state "testflight")
+  run grep "dirty" $(state "testflight")
+  [ "$status" -ne 0 ]
+
+  cmd reproduce -e testflight --output ${BATS_TMPDIR}/file_mode_out
+  [ -L ${BATS_TMPDIR}/file_mode_out/$(fixture)/link.sh ]
+  [ -x ${BATS_TMPDIR}/file_mode_out/$(fixture)/script.sh ]
+
+  cmd reproduce -e testflight --output ${BATS_TMPDIR}/file_mode_out
+  [ -L ${BATS_TMPDIR}/file_mode_out/$(fixture)/link.sh ]
+}
@@ -0,0 +1,48 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'first_parent'"
+  prepare_test "first_parent"
+}
+
+teardown_file() {
+  echo "Tearing down 'first_parent'"
+  reset_repo_state
+}
+
+merge_change() {
+  git checkout -b first_parent_$1
+  echo "field: $1" > $(fixture)/file.yml
+  git commit -am "Change file on $1"
+  cache_value "feature" $(git rev-parse HEAD)
+  git checkout first_parent
+  git merge --no-ff -m "Merge $1" first_parent_$1
+  git branch -D first_parent_$1
+}
+
+@test "Records the feature branch commit by default" {
+  merge_change "feature_a"
+  cmd record -e testflight
+  grep "from_commit: $(read_value "feature")" $(state "testflight")
+  grep "message: Change file on feature_a" $(state "testflight")
+}
+
+@test "Records the merge commit with --first-parent" {
+  merge_change "feature_b"
+  merge=$(git rev-parse HEAD)
+  cmd --first-parent record -e testflight
+  grep "from_commit: ${merge}" $(state "testflight")
+  grep "message: Merge feature_b" $(state "testflight")
+}
+
+@test "Records the merge commit with 'first_parent' in the config" {
+  echo "first_parent: true" >> $(config)
+  git commit -am 'Enable first parent mode'
+  merge_change "feature_c"
+  merge=$(git rev-parse HEAD)
+  cmd record -e testflight
+  grep "from_commit: ${merge}" $(state "testflight")
+  grep "message: Merge feature_c" $(state "testflight")
+}
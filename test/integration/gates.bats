@@ -14,7 +14,7 @@ teardown_file() {
 
 @test "Fails when gate isn't specified" {
   run cmd -g `fixture`/cepler-gates.yml check -e missing
-  [ "$status" -eq 1 ]
+  [ "$status" -eq 15 ]
 }
 
 @test "Takes latest when its HEAD" {
@@ -1,10 +1,15 @@
 REPO_ROOT=$(git rev-parse --show-toplevel)
 
-cmd() {
-  cepler=${REPO_ROOT}/target/debug/cepler
+cepler_bin() {
   if [[ ! -z ${CARGO_TARGET_DIR} ]] ; then
-    cepler=${CARGO_TARGET_DIR}/debug/cepler
+    echo ${CARGO_TARGET_DIR}/debug/cepler
+  else
+    echo ${REPO_ROOT}/target/debug/cepler
   fi
+}
+
+cmd() {
+  cepler=$(cepler_bin)
 
   echo "${cepler} -c test/fixtures/$(basename ${BATS_TEST_FILENAME%%.*})/cepler.yml $@"
 
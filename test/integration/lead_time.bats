@@ -0,0 +1,54 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'lead_time'"
+  prepare_test "lead_time"
+}
+
+teardown_file() {
+  echo "Tearing down 'lead_time'"
+  reset_repo_state
+}
+
+@test "Reports lead time and deployments per environment" {
+  echo "field: old" > $(fixture)/file.yml
+  GIT_AUTHOR_DATE="2021-01-01T00:00:00Z" git commit -am 'Old change'
+  cmd record -e testflight
+  cmd record -e staging
+  echo "field: new" > $(fixture)/file.yml
+  GIT_AUTHOR_DATE="2022-01-01T00:00:00Z" git commit -am 'New change'
+  cmd record -e testflight
+
+  csv=$(cmd report lead-time)
+  echo "${csv}" | grep "^deployment,environment,deployments,"
+  echo "${csv}" | grep "^default,staging,1,"
+  echo "${csv}" | grep "^default,testflight,2,"
+
+  json=$(cmd report lead-time -e staging --format json)
+  echo "${json}" | grep '"changes": 0'
+  echo "${json}" | grep '"median_lead_time_seconds": null'
+
+  json=$(cmd report lead-time -e testflight --format json)
+  echo "${json}" | grep '"changes": 1'
+  median=$(echo "${json}" | grep median_lead_time_seconds | grep -o '[0-9]*')
+  [ "${median}" -gt $((365 * 24 * 60 * 60)) ]
+  [ "$(echo "${json}" | grep -c staging)" == "0" ]
+}
+
+@test "Counts partial and full records of the same trigger as separate deployments" {
+  echo "field: partial" > $(fixture)/file.yml
+  echo "field: partial" > $(fixture)/other.yml
+  git commit -am 'Change both files'
+  cmd record -e testflight --only $(fixture)/file.yml
+  cmd record -e testflight
+
+  cmd report lead-time -e testflight | grep "^default,testflight,4,"
+  json=$(cmd report lead-time -e testflight --format json)
+  echo "${json}" | grep '"changes": 3'
+  cmd reproduce -e testflight --at 1 --output ${BATS_TMPDIR}/lead_time_out
+  grep "field: partial" ${BATS_TMPDIR}/lead_time_out/$(fixture)/file.yml
+  grep "field: value" ${BATS_TMPDIR}/lead_time_out/$(fixture)/other.yml
+  rm -rf ${BATS_TMPDIR}/lead_time_out
+}
@@ -0,0 +1,72 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'lfs'"
+  prepare_test "lfs"
+}
+
+teardown_file() {
+  echo "Tearing down 'lfs'"
+  rm -rf $(git rev-parse --git-dir)/lfs
+  reset_repo_state
+}
+
+add_lfs_file() {
+  echo "$2" > ${BATS_TMPDIR}/lfs_content
+  oid=$(sha256sum ${BATS_TMPDIR}/lfs_content | cut -d' ' -f1)
+  store=$(git rev-parse --git-dir)/lfs/objects/${oid:0:2}/${oid:2:2}
+  mkdir -p ${store}
+  cp ${BATS_TMPDIR}/lfs_content ${store}/${oid}
+  cat <<POINTER > $1
+version https://git-lfs.github.com/spec/v1
+oid sha256:${oid}
+size $(wc -c < ${BATS_TMPDIR}/lfs_content)
+POINTER
+  echo ${oid}
+}
+
+@test "Prepare materializes LFS files and record compares object ids" {
+  oid=$(add_lfs_file $(fixture)/file.bin "lfs content")
+  git add $(fixture)/file.bin
+  git commit -m 'Add lfs file'
+
+  cmd prepare -e testflight
+  grep "lfs content" $(fixture)/file.bin
+  cmd record -e testflight
+  grep "lfs_oid: ${oid}" $(state "testflight")
+  run grep "dirty" $(state "testflight")
+  [ "$status" -ne 0 ]
+}
+
+@test "Propagated LFS files are materialized" {
+  git checkout $(fixture)/file.bin
+  cmd prepare -e staging
+  grep "lfs content" $(fixture)/file.bin
+  cmd record -e staging
+  run grep "dirty" $(state "staging")
+  [ "$status" -ne 0 ]
+}
+
+@test "LFS files are materialized in linked worktrees" {
+  worktree=${BATS_TMPDIR}/lfs_worktree
+  rm -rf ${worktree}
+  git worktree add --detach ${worktree} HEAD
+  cd ${worktree}
+  $(cepler_bin) -c $(config) prepare -e staging
+  grep "lfs content" $(fixture)/file.bin
+  cd ${REPO_ROOT}
+  git worktree remove --force ${worktree}
+}
+
+@test "Diff shows the object ids of LFS files" {
+  old_oid=$(grep "lfs_oid:" $(state "staging") | awk '{ print $2 }')
+  new_oid=$(add_lfs_file $(fixture)/file.bin "new lfs content")
+  git commit -am 'Update lfs file'
+  cmd record -e testflight
+
+  cmd diff -e staging --content | grep "LFS object $(fixture)/file.bin: oid ${old_oid} → ${new_oid}"
+  run bash -c "$(cepler_bin) -c $(config) diff -e staging --content | grep 'version https'"
+  [ "$status" -ne 0 ]
+}
@@ -0,0 +1,50 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'lock'"
+  prepare_test "lock"
+}
+
+teardown_file() {
+  echo "Tearing down 'lock'"
+  reset_repo_state
+}
+
+lock_file() {
+  echo "test/fixtures/$(basename ${BATS_TEST_FILENAME%%.*})/.cepler/default/$1.lock"
+}
+
+@test "Locked environment has nothing to deploy" {
+  cmd record -e testflight
+  cmd check -e production
+
+  cmd lock -e production --reason release-freeze
+  grep release-freeze $(lock_file "production")
+  git log -1 --format=%s | grep "Locked 'production'"
+
+  run cmd check -e production
+  [ "$status" -eq 2 ]
+  cmd status | grep release-freeze
+}
+
+@test "Prepare and record refuse locked environment" {
+  run cmd prepare -e production
+  [ "$status" -eq 14 ]
+  run cmd record -e production
+  [ "$status" -eq 14 ]
+
+  cmd prepare -e production --force
+  cmd record -e production --force
+  grep release-freeze $(lock_file "production")
+}
+
+@test "Unlock removes the lock" {
+  cmd unlock -e production
+  [ ! -f $(lock_file "production") ]
+  git log -1 --format=%s | grep "Unlocked 'production'"
+  run cmd status -e production
+  [ "$status" -eq 0 ]
+  [[ "$output" != *"locked"* ]]
+}
@@ -0,0 +1,30 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'message_template'"
+  prepare_test "message_template"
+}
+
+teardown_file() {
+  echo "Tearing down 'message_template'"
+  reset_repo_state
+}
+
+@test "Renders the state commit message from the template" {
+  trigger=$(git rev-parse HEAD)
+  cmd record -e testflight
+  [ "$(git log -1 --format=%s)" == "Deployed ${trigger:0:7} to default/testflight (2 files)" ]
+  git log -1 --format=%b | grep "^- test/fixtures/message_template/file.yml ([0-9a-f]\{7\} .*)$"
+  git log -1 --format=%b | grep "^- test/fixtures/message_template/other.yml ([0-9a-f]\{7\} .*)$"
+}
+
+@test "Lists only the changed files" {
+  echo "field: changed" > $(fixture)/file.yml
+  git commit -am 'Change file'
+  trigger=$(git rev-parse HEAD)
+  cmd record -e testflight
+  [ "$(git log -1 --format=%s)" == "Deployed ${trigger:0:7} to default/testflight (1 files)" ]
+  [ "$(git log -1 --format=%b)" == "- test/fixtures/message_template/file.yml (${trigger:0:7} Change file)" ]
+}
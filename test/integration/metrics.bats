@@ -0,0 +1,58 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'metrics'"
+  prepare_test "metrics"
+}
+
+teardown_file() {
+  echo "Tearing down 'metrics'"
+  reset_repo_state
+}
+
+labels() {
+  echo "{deployment=\"default\",environment=\"$1\"}"
+}
+
+@test "Reports pending files before the first record" {
+  metrics=$(cmd metrics)
+  echo "${metrics}" | grep "# TYPE cepler_pending_files gauge"
+  echo "${metrics}" | grep "cepler_pending_files$(labels testflight) 1"
+  [ "$(echo "${metrics}" | grep -c "^cepler_last_record_timestamp_seconds")" == "0" ]
+}
+
+trigger() {
+  grep -m 1 head_commit $(state $1) | awk '{print $2}'
+}
+
+@test "Reports lag behind the upstream environment" {
+  cmd record -e testflight
+  cmd record -e staging
+  for value in first second; do
+    echo "field: ${value}" > $(fixture)/file.yml
+    git commit -am "Update file.yml to ${value}"
+    cmd record -e testflight
+  done
+
+  metrics=$(cmd metrics)
+  echo "${metrics}" | grep "cepler_last_record_timestamp_seconds$(labels staging) [0-9]*$"
+  echo "${metrics}" | grep "cepler_pending_files$(labels testflight) 0"
+  echo "${metrics}" | grep "cepler_pending_files$(labels staging) 1"
+  echo "${metrics}" | grep "cepler_upstream_queue_length$(labels staging) 1"
+  behind=$(git rev-list --count $(trigger staging)..$(trigger testflight))
+  [ "${behind}" -gt 0 ]
+  echo "${metrics}" | grep "cepler_commits_behind_upstream$(labels staging) ${behind}"
+}
+
+@test "Reports pending files of locked environments" {
+  cmd lock -e staging --reason freeze
+  metrics=$(cmd metrics)
+  echo "${metrics}" | grep "# TYPE cepler_locked gauge"
+  echo "${metrics}" | grep "cepler_locked$(labels staging) 1"
+  echo "${metrics}" | grep "cepler_locked$(labels testflight) 0"
+  echo "${metrics}" | grep "cepler_pending_files$(labels staging) 1"
+  cmd diff -e staging | grep $(fixture)/file.yml
+  cmd unlock -e staging
+}
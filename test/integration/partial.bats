@@ -0,0 +1,68 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'partial'"
+  prepare_test "partial"
+}
+
+teardown_file() {
+  echo "Tearing down 'partial'"
+  reset_repo_state
+}
+
+@test "Prepare only selected files" {
+  cmd record -e testflight
+  cmd record -e production
+
+  echo "image_hotfix: {}" > `fixture`/image.yml
+  echo "chart_new: {}" > `fixture`/chart.yml
+  git commit -am 'Update image.yml and chart.yml'
+  cmd record -e testflight
+
+  cmd prepare -e production --only `fixture`/image.yml
+  grep 'image_hotfix' `fixture`/image.yml
+  run grep 'chart_new' `fixture`/chart.yml
+  [ "$status" -eq 1 ]
+}
+
+@test "Record only selected files keeps remaining files pending" {
+  cmd record -e production --only `fixture`/image.yml
+  grep 'only:' $(state "production")
+  run grep 'dirty' $(state "production")
+  [ "$status" -eq 1 ]
+
+  cmd check -e production 2>&1 | grep "chart.yml changed"
+  cmd prepare -e production
+  grep 'chart_new' `fixture`/chart.yml
+  cmd record -e production
+  run grep 'only:' $(state "production")
+  [ "$status" -eq 1 ]
+
+  run cmd check -e production
+  [ "$status" -eq 2 ]
+}
+
+@test "Concourse check emits a version for files still pending" {
+  echo "image_next: {}" > `fixture`/image.yml
+  echo "chart_next: {}" > `fixture`/chart.yml
+  git commit -am 'Update image.yml and chart.yml again'
+  cmd record -e testflight
+  cmd record -e production --only `fixture`/image.yml
+  trigger=$(grep 'head_commit:' $(state "production") | head -n 1 | awk '{ print $2 }')
+
+  tmp=${BATS_TMPDIR}/partial_concourse
+  rm -rf ${tmp} && mkdir -p ${tmp}
+  input="{\"source\": {\"uri\": \"${REPO_ROOT}\", \"branch\": \"partial\", \"environment\": \"production\", \"config\": \"$(config)\"}, \"version\": {\"trigger\": \"${trigger}\"}}"
+  versions=$(echo "${input}" | TMPDIR=${tmp} $(cepler_bin) concourse check)
+  echo "${versions}"
+  [ "$(echo "${versions}" | grep -o '"trigger"' | wc -l)" -eq 2 ]
+  echo "${versions}" | grep '"pending"'
+
+  cmd record -e production
+  last=$(echo "${versions}" | sed 's/.*\({"trigger"[^}]*}\)\]$/\1/')
+  input="{\"source\": {\"uri\": \"${REPO_ROOT}\", \"branch\": \"partial\", \"environment\": \"production\", \"config\": \"$(config)\"}, \"version\": ${last}}"
+  versions=$(echo "${input}" | TMPDIR=${tmp} $(cepler_bin) concourse check)
+  [ "$(echo "${versions}" | grep -o '"trigger"' | wc -l)" -eq 1 ]
+}
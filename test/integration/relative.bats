@@ -0,0 +1,43 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'relative'"
+  prepare_test "relative"
+}
+
+teardown_file() {
+  echo "Tearing down 'relative'"
+  reset_repo_state
+}
+
+@test "Globs are relative to the config" {
+  cmd ls -e testflight | grep "$(fixture)/deploy/file.yml"
+  cmd record -e testflight
+  grep "$(fixture)/deploy/file.yml" $(state "testflight")
+}
+
+@test "Prepare staging resets files relative to the config" {
+  file_hash=$(git hash-object $(fixture)/deploy/file.yml)
+  echo "field: new" > $(fixture)/deploy/file.yml
+  git commit -am 'Update file.yml'
+
+  cmd prepare -e staging
+  [ "$(git hash-object $(fixture)/deploy/file.yml)" == "${file_hash}" ]
+  cmd record -e staging
+  grep ${file_hash} $(state "staging")
+
+  git checkout .
+}
+
+@test "Only globs are relative to the config" {
+  echo "field: other" > $(fixture)/deploy/other.yml
+  echo "field: newer" > $(fixture)/deploy/file.yml
+  git add $(fixture)
+  git commit -m 'Add other.yml'
+
+  cmd record -e testflight --only deploy/other.yml
+  grep "$(fixture)/deploy/other.yml" $(state "testflight")
+  cmd check -e testflight 2>&1 | grep "file.yml changed"
+}
@@ -0,0 +1,47 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'repo_dir'"
+  prepare_test "repo_dir"
+}
+
+teardown_file() {
+  echo "Tearing down 'repo_dir'"
+  reset_repo_state
+}
+
+cepler() {
+  $(cepler_bin) $@
+}
+
+@test "Runs from a subdirectory" {
+  cd $(fixture)
+  cepler -c cepler.yml ls -e testflight | grep "$(fixture)/file.yml"
+  cepler -c cepler.yml record -e testflight
+  cd ${REPO_ROOT}
+  grep $(git hash-object $(fixture)/file.yml) $(state "testflight")
+}
+
+@test "Runs against --repo from outside the repository" {
+  file_hash=$(git hash-object $(fixture)/file.yml)
+  echo "field: new" > $(fixture)/file.yml
+  git commit -am 'Update file.yml'
+  config=$(config)
+
+  cd ${BATS_TMPDIR}
+  cepler --repo ${REPO_ROOT} -c ${config} prepare -e staging
+  cepler --repo ${REPO_ROOT} -c ${config} latest -e testflight
+  cd ${REPO_ROOT}
+  [ "$(git hash-object $(fixture)/file.yml)" == "${file_hash}" ]
+
+  git checkout .
+}
+
+@test "Reports configs resolved relative to the repository root" {
+  config=$(config)
+  cd ${BATS_TMPDIR}
+  cepler --repo ${REPO_ROOT} -c ${config} ls -e testflight 2>&1 \
+    | grep "'${config}' doesn't exist in the current directory, using '${REPO_ROOT}/${config}'"
+}
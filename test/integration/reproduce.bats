@@ -0,0 +1,64 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'reproduce'"
+  prepare_test "reproduce"
+}
+
+teardown_file() {
+  echo "Tearing down 'reproduce'"
+  rm -rf ${BATS_TMPDIR}/reproduce_out
+  reset_repo_state
+}
+
+@test "Reproduce a previous state" {
+  cache_value "first_trigger" $(git rev-parse --short HEAD)
+  cmd record -e testflight
+  echo "version: 2" > $(fixture)/file.yml
+  git commit -am 'Update file'
+  cmd record -e testflight
+
+  cmd reproduce -e testflight --at 1 --output ${BATS_TMPDIR}/reproduce_out
+  grep "version: 1" ${BATS_TMPDIR}/reproduce_out/$(fixture)/file.yml
+  grep "version: 2" $(fixture)/file.yml
+
+  cmd reproduce -e testflight --at $(read_value "first_trigger")
+  grep "version: 1" $(fixture)/file.yml
+
+  cmd reproduce -e testflight --at 0
+  grep "version: 2" $(fixture)/file.yml
+}
+
+@test "Reproduce fails for unknown states" {
+  run cmd reproduce -e testflight --at 5
+  [ "$status" -eq 1 ]
+  echo "$output" | grep "No state recorded"
+}
+
+@test "Reproduce fails for files missing from their commit" {
+  sed -i "s#reproduce/file.yml#reproduce/missing.yml#" $(state "testflight")
+  run cmd reproduce -e testflight --output ${BATS_TMPDIR}/reproduce_out
+  [ "$status" -eq 1 ]
+  echo "$output" | grep "reproduce/missing.yml' not found"
+  git checkout $(state "testflight")
+}
+
+@test "Reproduce replaces symlinks and permissions in the output" {
+  out=${BATS_TMPDIR}/reproduce_out
+  rm -rf ${out}
+  mkdir -p ${out}/$(fixture)
+  echo "outside" > ${BATS_TMPDIR}/reproduce_outside
+  ln -s ${BATS_TMPDIR}/reproduce_outside ${out}/$(fixture)/file.yml
+
+  cmd reproduce -e testflight --output ${out}
+  [ ! -L ${out}/$(fixture)/file.yml ]
+  grep "version: 2" ${out}/$(fixture)/file.yml
+  grep "outside" ${BATS_TMPDIR}/reproduce_outside
+
+  chmod 755 ${out}/$(fixture)/file.yml
+  cmd reproduce -e testflight --output ${out}
+  [ ! -x ${out}/$(fixture)/file.yml ]
+  rm -f ${BATS_TMPDIR}/reproduce_outside
+}
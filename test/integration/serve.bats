@@ -0,0 +1,48 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'serve'"
+  prepare_test "serve"
+}
+
+teardown_file() {
+  echo "Tearing down 'serve'"
+  reset_repo_state
+}
+
+address=127.0.0.1:18431
+
+start_server() {
+  $(cepler_bin) -c $(config) serve --listen ${address} --refresh 1 3>&- &
+  server_pid=$!
+  for i in $(seq 1 20); do
+    curl -s http://${address}/environments > /dev/null && return 0
+    sleep 0.2
+  done
+  return 1
+}
+
+@test "Serves environments, state, diff and history" {
+  $(cepler_bin) -c $(config) record -e testflight
+  start_server
+
+  environments=$(curl -s http://${address}/environments)
+  state=$(curl -s http://${address}/environments/default/testflight)
+  diff=$(curl -s http://${address}/environments/default/staging/diff)
+  history=$(curl -s http://${address}/environments/default/testflight/history)
+  metrics=$(curl -s http://${address}/metrics)
+  status=$(curl -s -o /dev/null -w '%{http_code}' http://${address}/environments/default/unknown)
+  kill ${server_pid}
+
+  echo "${environments}" | grep '"environment":"staging"'
+  echo "${environments}" | grep "\"trigger\":\"$(git rev-parse HEAD~1)\""
+  echo "${state}" | grep '"passed":null'
+  echo "${state}" | grep 'test/fixtures/serve/file.yml'
+  echo "${diff}" | grep '"change":"added"'
+  echo "${diff}" | grep '"file":"test/fixtures/serve/file.yml"'
+  [ "$(echo "${history}" | grep -o head_commit | wc -l)" == "1" ]
+  [ "${status}" == "404" ]
+  echo "${metrics}" | grep 'cepler_pending_files{deployment="default",environment="testflight"} 0'
+}
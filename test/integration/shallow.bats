@@ -0,0 +1,122 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'shallow'"
+  prepare_test "shallow"
+}
+
+teardown_file() {
+  echo "Tearing down 'shallow'"
+  reset_repo_state
+}
+
+remote=${BATS_TMPDIR}/shallow_remote.git
+
+@test "Deepens a shallow clone to find the commit that changed a file" {
+  echo "field: recorded" > $(fixture)/file.yml
+  git commit -am 'Change file'
+  cache_value "changed" $(git rev-parse HEAD)
+  for i in 1 2 3; do
+    git commit --allow-empty -m "Unrelated change ${i}"
+  done
+  rm -rf ${remote}
+  git clone --bare ${REPO_ROOT} ${remote}
+  git --git-dir=${remote} config uploadpack.allowFilter true
+
+  clone=${BATS_TMPDIR}/shallow_clone
+  rm -rf ${clone}
+  $(cepler_bin) --clone ${clone} --clone-depth 1 --git-url file://${remote} --git-branch shallow -c $(config) \
+    record -e testflight
+  cd ${clone}
+  [ "$(git rev-list --count HEAD)" -gt 2 ]
+  grep "from_commit: $(read_value "changed")" $(state "testflight")
+  grep "message: Change file" $(state "testflight")
+}
+
+@test "Fetches file contents missing from a blobless clone" {
+  cmd record -e testflight
+  echo "field: not recorded" > $(fixture)/file.yml
+  git commit -am 'Change file again'
+  git push --force file://${remote} shallow
+
+  clone=${BATS_TMPDIR}/shallow_blobless
+  rm -rf ${clone}
+  $(cepler_bin) --clone ${clone} --clone-blobless --git-url file://${remote} --git-branch shallow -c $(config) \
+    prepare -e staging
+  cd ${clone}
+  [ "$(git config remote.origin.promisor)" == "true" ]
+  [ "$(cat $(fixture)/file.yml)" == "field: recorded" ]
+}
+
+@test "Pushes from a shallow blobless clone" {
+  clone=${BATS_TMPDIR}/shallow_push
+  rm -rf ${clone}
+  $(cepler_bin) --clone ${clone} --clone-depth 1 --clone-blobless --git-url file://${remote} --git-branch shallow -c $(config) \
+    prepare -e staging
+
+  # Advance the remote so recording has to rebase onto it
+  echo "field: upstream" > $(fixture)/other.yml
+  git add $(fixture)/other.yml
+  git commit -m 'Unrelated change'
+  git push file://${remote} shallow
+
+  cd ${clone}
+  $(cepler_bin) -c $(config) record -e staging --reset-head --push --git-url file://${remote} --git-branch shallow
+  [ "$(git --git-dir=${remote} rev-parse shallow)" == "$(git rev-parse HEAD)" ]
+  [ "$(cat $(fixture)/other.yml)" == "field: upstream" ]
+}
+
+@test "Credentials are not stored in the clone" {
+  clone=${BATS_TMPDIR}/shallow_credentials
+  rm -rf ${clone}
+  $(cepler_bin) --clone ${clone} --clone-depth 1 --git-url file://${remote} --git-branch shallow \
+    --git-username deploy --git-password not-stored -c $(config) ls -e testflight
+  run grep -r "not-stored" ${clone}/.git
+  [ "$status" -ne 0 ]
+  run git -C ${clone} config --local --get-regexp '^(core.sshcommand|credential)'
+  [ "$status" -ne 0 ]
+}
+
+@test "Reports pushes rejected by the remote" {
+  clone=${BATS_TMPDIR}/shallow_rejected
+  rm -rf ${clone}
+  $(cepler_bin) --clone ${clone} --clone-blobless --git-url file://${remote} --git-branch shallow -c $(config) \
+    prepare -e testflight
+  printf '#!/bin/sh\nexit 1\n' > ${remote}/hooks/pre-receive
+  chmod +x ${remote}/hooks/pre-receive
+
+  cd ${clone}
+  run $(cepler_bin) -c $(config) record -e testflight --reset-head --push --git-url file://${remote} --git-branch shallow
+  rm ${remote}/hooks/pre-receive
+  [ "$status" -eq 21 ]
+  echo "$output" | grep "was rejected"
+}
+
+@test "Gives up on commits missing from the full history" {
+  sed -i "s/from_commit: .*/from_commit: 0123456789012345678901234567890123456789/" $(state "testflight")
+  git commit -am 'Record a commit that does not exist'
+  for i in $(seq 1 400); do
+    git commit -q --allow-empty -m "Filler ${i}"
+  done
+  git push --force file://${remote} shallow
+
+  # Log the invocations of the git cli
+  bin=${BATS_TMPDIR}/shallow_bin
+  mkdir -p ${bin}
+  printf '#!/bin/sh\necho "$@" >> %s/git.log\nexec %s "$@"\n' ${bin} $(command -v git) > ${bin}/git
+  chmod +x ${bin}/git
+  rm -f ${bin}/git.log
+
+  clone=${BATS_TMPDIR}/shallow_bogus
+  rm -rf ${clone}
+  run env PATH=${bin}:${PATH} $(cepler_bin) --clone ${clone} --clone-depth 1 --git-url file://${remote} --git-branch shallow \
+    -c $(config) reproduce -e testflight --output ${BATS_TMPDIR}/shallow_out
+  [ "$status" -eq 1 ]
+  echo "$output" | grep "Commit '0123456789012345678901234567890123456789' not found"
+  [ ! -f ${clone}/.git/shallow ]
+  [ "$(grep -c -- "--deepen" ${bin}/git.log)" -le 3 ]
+  [ "$(grep -c -- "--unshallow" ${bin}/git.log)" -eq 1 ]
+  rm -rf ${bin} ${BATS_TMPDIR}/shallow_out
+}
@@ -0,0 +1,52 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'signing'"
+  prepare_test "signing"
+}
+
+teardown_file() {
+  echo "Tearing down 'signing'"
+  reset_repo_state
+}
+
+@test "Commits state with the configured author" {
+  cmd record -e testflight
+  [ "$(git log -1 --format='%an <%ae>')" == "Deploy Bot <deploy@example.com>" ]
+  [ "$(git log -1 --format='%cn <%ce>')" == "Deploy Bot <deploy@example.com>" ]
+}
+
+@test "Signs state commits with an ssh key" {
+  key=${BATS_TMPDIR}/signing_key
+  rm -f ${key} ${key}.pub
+  ssh-keygen -q -t ed25519 -N "" -C "" -f ${key}
+  echo "deploy@example.com $(cat ${key}.pub)" > ${BATS_TMPDIR}/allowed_signers
+
+  echo "field: changed" > $(fixture)/file.yml
+  CEPLER_SIGNING_KEY=${key} CEPLER_SIGNING_FORMAT=ssh cmd record -e testflight
+  git cat-file commit HEAD | grep "BEGIN SSH SIGNATURE"
+  git -c gpg.format=ssh -c gpg.ssh.allowedSignersFile=${BATS_TMPDIR}/allowed_signers verify-commit HEAD
+}
+
+@test "Signs commits rebased while pushing" {
+  key=${BATS_TMPDIR}/signing_key
+  remote=${BATS_TMPDIR}/signing_remote.git
+  clone=${BATS_TMPDIR}/signing_clone
+  rm -rf ${remote} ${clone}
+  git clone --bare ${REPO_ROOT} ${remote}
+  git clone --branch signing ${remote} ${clone}
+
+  # Advance the remote so recording has to rebase onto it
+  git commit --allow-empty -m 'Unrelated change'
+  git push file://${remote} signing
+
+  cd ${clone}
+  echo "field: rebased" > $(fixture)/file.yml
+  CEPLER_SIGNING_KEY=${key} CEPLER_SIGNING_FORMAT=ssh \
+    $(cepler_bin) -c $(config) record -e testflight --reset-head --push --git-url file://${remote} --git-branch signing
+  [ "$(git --git-dir=${remote} rev-parse signing)" == "$(git rev-parse HEAD)" ]
+  [ "$(git log -1 --format=%s HEAD~1)" == "Unrelated change" ]
+  git -c gpg.format=ssh -c gpg.ssh.allowedSignersFile=${BATS_TMPDIR}/allowed_signers verify-commit HEAD
+}
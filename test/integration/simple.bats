@@ -18,7 +18,7 @@ teardown_file() {
 
 @test "Staging doesn't need deploying" {
   run cmd check -e staging
-  [ "$status" -eq 1 ]
+  [ "$status" -eq 12 ]
 }
 
 @test "Record testflight should hash file" {
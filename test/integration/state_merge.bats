@@ -0,0 +1,61 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'state_merge'"
+  prepare_test "state_merge"
+}
+
+teardown_file() {
+  echo "Tearing down 'state_merge'"
+  reset_repo_state
+}
+
+remote=${BATS_TMPDIR}/state_merge_remote.git
+
+clone() {
+  rm -rf ${BATS_TMPDIR}/$1
+  git clone --branch state_merge ${remote} ${BATS_TMPDIR}/$1
+}
+
+record() {
+  $(cepler_bin) -c $(config) record -e testflight --reset-head --push --git-url file://${remote} --git-branch state_merge $@
+}
+
+@test "Merges states recorded concurrently from the same trigger" {
+  cmd record -e testflight
+  echo "field: a changed" > $(fixture)/a.yml
+  echo "field: b changed" > $(fixture)/b.yml
+  git commit -am 'Change a and b'
+  rm -rf ${remote}
+  git clone --bare ${REPO_ROOT} ${remote}
+  clone "state_merge_first"
+  clone "state_merge_second"
+
+  cd ${BATS_TMPDIR}/state_merge_first
+  record --only "test/fixtures/state_merge/a.yml"
+  cd ${BATS_TMPDIR}/state_merge_second
+  record --only "test/fixtures/state_merge/b.yml"
+
+  [ "$(git --git-dir=${remote} rev-parse state_merge)" == "$(git rev-parse HEAD)" ]
+  [ "$(git log -1 --format=%s HEAD~1)" == "[cepler] Updated 'testflight' state" ]
+  run $(cepler_bin) -c $(config) check -e testflight
+  [ "$status" -eq 2 ]
+}
+
+@test "Fails on states recorded concurrently from different triggers" {
+  clone "state_merge_first"
+  clone "state_merge_second"
+
+  cd ${BATS_TMPDIR}/state_merge_first
+  echo "field: a first" > $(fixture)/a.yml
+  git commit -am 'Change a in first'
+  record
+  cd ${BATS_TMPDIR}/state_merge_second
+  echo "field: b second" > $(fixture)/b.yml
+  git commit -am 'Change b in second'
+  run record
+  [ "$status" -eq 21 ]
+  echo "$output" | grep "Both sides changed the recorded trigger"
+}
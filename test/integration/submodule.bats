@@ -0,0 +1,60 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'submodule'"
+  prepare_test "submodule"
+}
+
+teardown_file() {
+  echo "Tearing down 'submodule'"
+  rm -rf $(fixture)/charts ${BATS_TMPDIR}/chart_origin
+  reset_repo_state
+}
+
+chart() {
+  echo "$(fixture)/charts/chart"
+}
+
+chart_head() {
+  git -C $(chart) rev-parse HEAD
+}
+
+@test "Submodule bump needs deploying" {
+  git init ${BATS_TMPDIR}/chart_origin
+  git -C ${BATS_TMPDIR}/chart_origin commit --allow-empty -m 'First'
+  git -c protocol.file.allow=always submodule add ${BATS_TMPDIR}/chart_origin $(chart)
+  git commit -m 'Add chart'
+  cache_value "first_chart" $(chart_head)
+  cmd record -e testflight
+  grep "mode: submodule" $(state "testflight")
+  grep $(read_value "first_chart") $(state "testflight")
+
+  git -C ${BATS_TMPDIR}/chart_origin commit --allow-empty -m 'Second'
+  git -C $(chart) pull
+  git add $(chart)
+  git commit -m 'Bump chart'
+  cmd check -e testflight
+  cmd record -e testflight
+  grep $(chart_head) $(state "testflight")
+  run grep "dirty" $(state "testflight")
+  [ "$status" -ne 0 ]
+}
+
+@test "Prepare checks out the recorded submodule commit" {
+  second_chart=$(chart_head)
+  git -C $(chart) checkout $(read_value "first_chart")
+  cmd prepare -e staging
+  [ "$(chart_head)" == "${second_chart}" ]
+  cmd record -e staging
+  grep ${second_chart} $(state "staging")
+}
+
+@test "Reproduce to an output dir names the submodules left empty" {
+  out=${BATS_TMPDIR}/submodule_out
+  rm -rf ${out}
+  cmd reproduce -e staging --output ${out} 2>&1 | grep "Submodule $(chart) is left empty, check out commit $(chart_head)"
+  [ -d ${out}/$(chart) ]
+  rm -rf ${out}
+}
@@ -0,0 +1,41 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'upstream_state'"
+  prepare_test "upstream_state"
+}
+
+teardown_file() {
+  echo "Tearing down 'upstream_state'"
+  reset_repo_state
+}
+
+@test "Prepare and record a specific upstream state" {
+  cmd record -e testflight
+  cmd record -e staging
+
+  for i in 1 2 3; do
+    echo "file_${i}: {}" > `fixture`/file.yml
+    git commit -am "Update file.yml ${i}"
+    cache_value "trigger_${i}" $(git rev-parse HEAD)
+    cmd record -e testflight
+  done
+
+  cmd prepare -e staging --from-upstream-state $(read_value "trigger_2")
+  grep 'file_2' `fixture`/file.yml
+  cmd record -e staging --from-upstream-state $(read_value "trigger_2")
+  grep $(read_value "trigger_2") $(state "staging")
+}
+
+@test "Refuses to go back to an older upstream state" {
+  run cmd prepare -e staging --from-upstream-state $(read_value "trigger_1")
+  [ "$status" -eq 1 ]
+
+  run cmd prepare -e staging --from-upstream-state deadbeef
+  [ "$status" -eq 1 ]
+
+  cmd prepare -e staging
+  grep 'file_3' `fixture`/file.yml
+}
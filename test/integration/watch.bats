@@ -0,0 +1,49 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'watch'"
+  prepare_test "watch"
+}
+
+teardown_file() {
+  echo "Tearing down 'watch'"
+  reset_repo_state
+}
+
+# Waits up to 10 seconds for file $1 to contain at least $2 lines
+wait_for_lines() {
+  for i in $(seq 1 50); do
+    [ -f $1 ] && [ "$(wc -l < $1)" -ge $2 ] && return 0
+    sleep 0.2
+  done
+  return 1
+}
+
+@test "Emits a JSON line per environment that needs deploying" {
+  output=$(cmd watch --once)
+  echo "${output}" | grep '"environment":"testflight"'
+  echo "${output}" | grep "\"trigger\":\"$(git rev-parse HEAD)\""
+  [ "$(echo "${output}" | grep -c staging)" == "0" ]
+}
+
+@test "Runs the command only for new triggers" {
+  events=${BATS_TMPDIR}/watch_events
+  rm -f ${events}
+  $(cepler_bin) -c $(config) watch --interval 1 -e testflight \
+    --exec "echo \${CEPLER_ENVIRONMENT} \${CEPLER_TRIGGER} >> ${events}" 3>&- &
+  pid=$!
+
+  wait_for_lines ${events} 1 || (kill ${pid}; false)
+  first_trigger=$(git rev-parse HEAD)
+
+  echo "field: new" > $(fixture)/file.yml
+  git commit -am 'Update file.yml'
+  wait_for_lines ${events} 2 || (kill ${pid}; false)
+  kill ${pid}
+
+  [ "$(wc -l < ${events})" == "2" ]
+  grep "testflight ${first_trigger}" ${events}
+  grep "testflight $(git rev-parse HEAD)" ${events}
+}